@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Consecutive failures before the breaker opens.
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long the breaker stays open before letting a trial request through.
+const OPEN_DURATION: Duration = Duration::from_secs(30);
+/// How long a trial request may go without a result before it counts as a
+/// failure. Titanic requests time out well before this, so it's only
+/// reached if the trial never recorded one, e.g. because it was cancelled.
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Circuit breaker for the titanic endpoint, so that submissions fail fast
+/// while it's down instead of each waiting on a timeout.
+#[derive(Debug)]
+pub enum CircuitBreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        since: Instant,
+    },
+    /// A single trial request is in flight.
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+impl Default for CircuitBreakerState {
+    fn default() -> Self {
+        CircuitBreakerState::Closed { failures: 0 }
+    }
+}
+
+impl CircuitBreakerState {
+    /// Whether a request may be made now. If not, returns how long until the
+    /// next one may be tried.
+    pub fn check(&mut self) -> Result<(), Duration> {
+        match *self {
+            CircuitBreakerState::Closed { .. } => Ok(()),
+            CircuitBreakerState::Open { since } => {
+                let elapsed = since.elapsed();
+                if elapsed >= OPEN_DURATION {
+                    info!("Titanic circuit breaker half-open, trying a request");
+                    *self = CircuitBreakerState::HalfOpen {
+                        since: Instant::now(),
+                    };
+                    Ok(())
+                } else {
+                    Err(OPEN_DURATION - elapsed)
+                }
+            }
+            CircuitBreakerState::HalfOpen { since } if since.elapsed() >= TRIAL_TIMEOUT => {
+                warn!("Titanic circuit breaker trial never finished, reopening");
+                *self = CircuitBreakerState::Open {
+                    since: Instant::now(),
+                };
+                Err(OPEN_DURATION)
+            }
+            // only the trial request goes through while half-open
+            CircuitBreakerState::HalfOpen { .. } => Err(Duration::ZERO),
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        if !matches!(self, CircuitBreakerState::Closed { .. }) {
+            info!("Titanic circuit breaker closed");
+        }
+        *self = CircuitBreakerState::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&mut self) {
+        match self {
+            CircuitBreakerState::Closed { failures } if *failures + 1 < FAILURE_THRESHOLD => {
+                *failures += 1;
+            }
+            CircuitBreakerState::Open { .. } => {}
+            _ => {
+                warn!("Titanic circuit breaker opened");
+                *self = CircuitBreakerState::Open {
+                    since: Instant::now(),
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow as ah;
+use std::{collections::VecDeque, sync::Mutex};
+
+use crate::{get_quotes_db, watcher::QuoteSchema, Quote};
+
+/// The most recent quotes, newest last, so that `/browse` and `/random`
+/// don't have to read the quotes db.
+#[derive(Debug)]
+pub struct QuoteCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    quotes: VecDeque<Quote>,
+    /// Whether `quotes` is every quote there is, which is only true until
+    /// one is evicted or forgotten.
+    complete: bool,
+}
+
+impl QuoteCache {
+    /// A cache holding up to `capacity` quotes, starting with the newest from
+    /// the quotes db.
+    pub fn load(capacity: usize) -> ah::Result<Self> {
+        let mut quotes = {
+            let conn = get_quotes_db()?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT id, quote, tags FROM {} ORDER BY id DESC LIMIT ?1",
+                QuoteSchema::detect(&conn)?.table()
+            ))?;
+            let quotes = stmt
+                // one extra to tell whether there are more than fit
+                .query_map([capacity + 1], Quote::from_row)?
+                .collect::<Result<VecDeque<_>, _>>()?;
+            quotes
+        };
+        let complete = quotes.len() <= capacity;
+        quotes.truncate(capacity);
+        quotes.make_contiguous().reverse();
+        Ok(Self::with_quotes(capacity, quotes, complete))
+    }
+
+    /// Load the cache again from the quotes db, for after it's been replaced.
+    pub fn reload(&self) -> ah::Result<()> {
+        let fresh = Self::load(self.capacity)?;
+        *self.inner.lock().unwrap() = fresh.inner.into_inner().unwrap();
+        Ok(())
+    }
+
+    fn with_quotes(capacity: usize, quotes: VecDeque<Quote>, complete: bool) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner { quotes, complete }),
+        }
+    }
+
+    /// Add a new quote, evicting the oldest if the cache is full. Quotes no
+    /// newer than the newest cached, e.g. resent after a reset, are ignored.
+    pub fn push(&self, quote: Quote) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner
+            .quotes
+            .back()
+            .is_some_and(|newest| newest.id >= quote.id)
+        {
+            return;
+        }
+        if inner.quotes.len() == self.capacity {
+            inner.quotes.pop_front();
+            inner.complete = false;
+        }
+        inner.quotes.push_back(quote);
+    }
+
+    /// Drop a quote that's been deleted or changed. The cache no longer has
+    /// every quote after this, so `all` falls back to the db.
+    pub fn forget(&self, id: i64) {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.quotes.len();
+        inner.quotes.retain(|quote| quote.id != id);
+        if inner.quotes.len() != len {
+            inner.complete = false;
+        }
+    }
+
+    /// The `count` newest quotes, newest first, if the cache has that many or
+    /// there aren't any more.
+    pub fn recent(&self, count: usize) -> Option<Vec<Quote>> {
+        let inner = self.inner.lock().unwrap();
+        (inner.complete || inner.quotes.len() >= count)
+            .then(|| inner.quotes.iter().rev().take(count).cloned().collect())
+    }
+
+    /// Every quote, oldest first, if they all fit in the cache.
+    pub fn all(&self) -> Option<Vec<Quote>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .complete
+            .then(|| inner.quotes.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(id: i64) -> Quote {
+        Quote {
+            id,
+            text: format!("quote {}", id),
+            tags: None,
+        }
+    }
+
+    fn ids(quotes: Option<Vec<Quote>>) -> Option<Vec<i64>> {
+        quotes.map(|quotes| quotes.iter().map(|q| q.id).collect())
+    }
+
+    #[test]
+    fn evicts_oldest_and_stops_being_complete() {
+        let cache = QuoteCache::with_quotes(3, (1..=2).map(quote).collect(), true);
+        assert_eq!(ids(cache.recent(5)), Some(vec![2, 1]));
+        cache.push(quote(3));
+        assert_eq!(ids(cache.all()), Some(vec![1, 2, 3]));
+        cache.push(quote(2));
+        assert_eq!(ids(cache.all()), Some(vec![1, 2, 3]));
+
+        cache.push(quote(4));
+        assert_eq!(ids(cache.recent(3)), Some(vec![4, 3, 2]));
+        // quote 1 is gone, so neither of these can be answered from the cache
+        assert_eq!(ids(cache.recent(4)), None);
+        assert_eq!(ids(cache.all()), None);
+    }
+
+    #[test]
+    fn forgetting_a_quote_makes_it_incomplete() {
+        let cache = QuoteCache::with_quotes(3, (1..=2).map(quote).collect(), true);
+        cache.forget(7);
+        assert_eq!(ids(cache.all()), Some(vec![1, 2]));
+        cache.forget(1);
+        assert_eq!(ids(cache.all()), None);
+        assert_eq!(ids(cache.recent(1)), Some(vec![2]));
+    }
+}
@@ -0,0 +1,1008 @@
+use ::config::{FileFormat, Value};
+use anyhow as ah;
+use once_cell::sync::OnceCell;
+use poise::serenity_prelude::{Activity, ChannelId, GuildId, OnlineStatus, RoleId, UserId};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+use crate::{crypto::CredentialCipher, get_args};
+
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    #[serde(serialize_with = "redact")]
+    pub token: String,
+    pub db_path: String,
+    pub quotes_channel_ids: Vec<ChannelId>,
+    pub quotes_db_path: String,
+    #[serde(serialize_with = "redact")]
+    pub credentials_key: String,
+    pub poll_interval: Duration,
+    pub admin_ids: Vec<UserId>,
+    pub log_dir: Option<String>,
+    pub log_level: String,
+    pub prefix: String,
+    /// Webhook urls carry their own token.
+    #[serde(serialize_with = "redact_all")]
+    pub webhook_urls: Vec<String>,
+    pub tag_colors: HashMap<String, u32>,
+    pub default_color: u32,
+    /// Titanic deployments users can register with and quote to.
+    pub servers: Vec<TitanicServer>,
+    pub default_server: String,
+    pub notify_role_id: Option<RoleId>,
+    pub db_pool_size: u32,
+    pub rate_limits: RateLimits,
+    pub shutdown_timeout: Duration,
+    /// Per command name, the role and user ids allowed to run it.
+    pub command_permissions: HashMap<String, Vec<u64>>,
+    /// Where to serve health and metrics endpoints, if anywhere.
+    pub metrics_bind_addr: Option<SocketAddr>,
+    /// First line of every quote sent to the quotes channels.
+    pub message_prefix: Option<String>,
+    /// Presence to set from the `[discord]` section, if there is one.
+    pub presence: Option<Presence>,
+    /// Quotes with any of these tags aren't posted to discord.
+    pub ignore_tags: Vec<String>,
+    pub replay_delay: Duration,
+    /// How long the poller may go without finishing a check, or spend sending
+    /// one quote, before the bot shuts down.
+    pub stall_timeout: Duration,
+    /// Where `/feedback` is sent: DMed to `admin_discord_id`, or posted in
+    /// `feedback_channel_id`, which wins if both are set.
+    pub admin_discord_id: Option<UserId>,
+    pub feedback_channel_id: Option<ChannelId>,
+    /// Minimum time between quote sends from the poller, from `discord_send_rate`
+    /// in messages per second, to stay under Discord's channel rate limit.
+    pub discord_send_interval: Duration,
+    /// Start a thread on every quote sent, for discussion.
+    pub create_threads: bool,
+    /// When to post the daily summary of new quotes, as time since midnight UTC,
+    /// from `daily_summary_time` in HH:MM.
+    pub daily_summary_time: Option<Duration>,
+    /// How many of the newest quotes to keep in memory.
+    pub cache_capacity: usize,
+    /// Bounds on the length of quotes sent with `/quote`, in characters.
+    pub quote_min_length: usize,
+    pub quote_max_length: usize,
+    /// Quotes db file changes within this long of the last one don't trigger
+    /// another poll.
+    pub debounce: Duration,
+    /// Thumbnail shown on quote embeds, e.g. a server logo.
+    pub embed_thumbnail_url: Option<String>,
+    /// How many polls the poller's average metrics cover.
+    pub poll_stats_capacity: usize,
+    /// Delete a user's credentials when they leave every server the bot is in,
+    /// which needs the privileged server members intent.
+    pub auto_delete_credentials: bool,
+    /// DM users whose credentials were deleted that way.
+    pub notify_deleted_credentials: bool,
+    /// Servers the bot's commands work in. Empty means every server.
+    pub guild_allowlist: Vec<GuildId>,
+    /// Warnings about deprecated keys in use, logged once logging is set up.
+    #[serde(skip)]
+    pub deprecations: Vec<String>,
+}
+
+/// Per-user limits from the `[rate_limits]` section, on commands run per minute.
+#[derive(Serialize, Deserialize)]
+pub struct RateLimits {
+    /// Across all commands.
+    pub global_per_minute: u32,
+    /// Per command name, from `<command>_per_minute` keys.
+    pub per_command: HashMap<String, u32>,
+}
+
+/// A titanic deployment, configured in its own `[name]` section.
+#[derive(Serialize, Deserialize)]
+pub struct TitanicServer {
+    pub name: String,
+    pub url: String,
+}
+
+/// The bot's Discord presence.
+#[derive(Serialize, Deserialize)]
+pub struct Presence {
+    pub status_text: Option<String>,
+    pub activity_type: ActivityType,
+    pub status: OnlineStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityType {
+    Playing,
+    Watching,
+    Listening,
+}
+
+impl Presence {
+    /// The activity to show, if there's any status text.
+    pub fn activity(&self) -> Option<Activity> {
+        let text = self.status_text.as_deref()?;
+        Some(match self.activity_type {
+            ActivityType::Playing => Activity::playing(text),
+            ActivityType::Watching => Activity::watching(text),
+            ActivityType::Listening => Activity::listening(text),
+        })
+    }
+}
+
+/// The file formats a config can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Ini,
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigSource {
+    /// Guess the format from the file extension, falling back to INI.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ConfigSource::Toml,
+            Some("json") => ConfigSource::Json,
+            Some("yaml" | "yml") => ConfigSource::Yaml,
+            _ => ConfigSource::Ini,
+        }
+    }
+
+    fn file_format(self) -> FileFormat {
+        match self {
+            ConfigSource::Ini => FileFormat::Ini,
+            ConfigSource::Toml => FileFormat::Toml,
+            ConfigSource::Json => FileFormat::Json,
+            ConfigSource::Yaml => FileFormat::Yaml,
+        }
+    }
+}
+
+impl FromStr for ConfigSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ini" => Ok(ConfigSource::Ini),
+            "toml" => Ok(ConfigSource::Toml),
+            "json" => Ok(ConfigSource::Json),
+            "yaml" => Ok(ConfigSource::Yaml),
+            _ => Err(format!(
+                "unknown config format {:?}, expected ini, toml, json or yaml",
+                s
+            )),
+        }
+    }
+}
+
+/// Environment variables from before `QUOTEBOT_` ones, still honored when
+/// the new name isn't set.
+const LEGACY_ENV_VARS: [(&str, &str); 5] = [
+    ("QUOTES_TOKEN", "token"),
+    ("QUOTES_CHANNEL_ID", "quotes_channel_id"),
+    ("QUOTES_DB_FILE", "db_file"),
+    ("QUOTES_DB_PATH", "quotes_db_path"),
+    ("QUOTES_CREDENTIALS_KEY", "credentials_key"),
+];
+
+/// Build the layered config: the config file, then `QUOTEBOT_` environment
+/// variables on top. A `QUOTEBOT_` variable names a top-level key, or a key
+/// in a section with a double underscore, e.g. `QUOTEBOT_METRICS__BIND_ADDR`.
+///
+/// `path` may be missing unless `required`.
+pub fn load_config(path: &Path, source: ConfigSource, required: bool) -> ah::Result<Layers> {
+    let mut builder = ::config::Config::builder()
+        .add_source(
+            ::config::File::new(&path.to_string_lossy(), source.file_format()).required(required),
+        )
+        .add_source(
+            ::config::Environment::with_prefix("QUOTEBOT")
+                .prefix_separator("_")
+                .separator("__")
+                .ignore_empty(true),
+        );
+    for (var, key) in LEGACY_ENV_VARS {
+        let new_var = format!("QUOTEBOT_{}", key.to_uppercase());
+        if std::env::var_os(new_var).is_none() {
+            builder = builder.set_override_option(key, std::env::var(var).ok())?;
+        }
+    }
+    Ok(Layers::new(builder.build()?))
+}
+
+/// Config values by section and key, like an INI file.
+///
+/// Top-level keys and keys in a `[default]` section, as INI files have them,
+/// are both in the `default` section. Lists may be arrays or comma-separated
+/// strings, and are read as the latter.
+///
+/// Values that can't be read are recorded rather than panicking, so every bad
+/// value can be reported at once; see `parse_config`.
+pub struct Layers {
+    config: ::config::Config,
+    errors: RefCell<Vec<String>>,
+}
+
+impl Layers {
+    fn new(config: ::config::Config) -> Self {
+        Self {
+            config,
+            errors: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn value(&self, section: &str, key: &str) -> Option<Value> {
+        if section == "default" {
+            self.config
+                .get::<Value>(key)
+                .or_else(|_| self.config.get::<Value>(&format!("default.{}", key)))
+                .ok()
+        } else {
+            self.config
+                .get::<Value>(&format!("{}.{}", section, key))
+                .ok()
+        }
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<String> {
+        self.value(section, key)
+            .and_then(|value| self.check(value_to_string(value).map(Some), key))
+    }
+
+    pub fn getuint(&self, section: &str, key: &str) -> Result<Option<u64>, String> {
+        self.get(section, key)
+            .map(|value| value.trim().parse::<u64>().map_err(|e| e.to_string()))
+            .transpose()
+    }
+
+    pub fn getbool(&self, section: &str, key: &str) -> Result<Option<bool>, String> {
+        self.get(section, key)
+            .map(|value| match value.trim().to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(true),
+                "false" | "no" | "off" | "0" => Ok(false),
+                other => Err(format!("{:?} isn't a boolean", other)),
+            })
+            .transpose()
+    }
+
+    /// Every key in a section, if it's there.
+    pub fn section(&self, section: &str) -> Option<Vec<(String, String)>> {
+        let table = self.config.get_table(section).ok()?;
+        Some(
+            table
+                .into_iter()
+                .filter_map(|(key, value)| {
+                    let value = self.check(
+                        value_to_string(value).map(Some),
+                        &format!("{}.{}", section, key),
+                    )?;
+                    Some((key, value))
+                })
+                .collect(),
+        )
+    }
+
+    /// Record a problem with the config.
+    pub fn error(&self, error: String) {
+        self.errors.borrow_mut().push(error);
+    }
+
+    /// Unwrap `result`, or record `what` went wrong and use the default.
+    pub fn check<T: Default, E: Display>(&self, result: Result<T, E>, what: &str) -> T {
+        result.unwrap_or_else(|e| {
+            self.error(format!("{}: {}", what, e));
+            T::default()
+        })
+    }
+
+    /// Unwrap a required value, or record `what` is missing.
+    pub fn require(&self, value: Option<String>, what: &str) -> String {
+        value.unwrap_or_else(|| {
+            self.error(what.to_owned());
+            String::new()
+        })
+    }
+}
+
+fn value_to_string(value: Value) -> Result<String, String> {
+    match value.clone().into_array() {
+        Ok(values) => values
+            .into_iter()
+            .map(value_to_string)
+            .collect::<Result<Vec<_>, _>>()
+            .map(|values| values.join(",")),
+        Err(_) => value
+            .into_string()
+            .map_err(|e| format!("must be a value or list: {}", e)),
+    }
+}
+
+/// Keep secrets out of `--dump-config`.
+fn redact<S: Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+/// `redact` each of a list, keeping how many there are.
+fn redact_all<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(|_| "<redacted>"))
+}
+
+/// Parse a color written as hex, e.g. `#ff0000` or `0xff0000`.
+fn parse_color(s: &str) -> Option<u32> {
+    let s = s.trim();
+    let hex = s
+        .strip_prefix('#')
+        .or_else(|| s.strip_prefix("0x"))
+        .unwrap_or(s);
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// Load the config on first use, panicking with every problem found if it's
+/// invalid.
+///
+/// Any key can also be set with a `QUOTEBOT_` environment variable, which
+/// takes precedence over the config file; see `load_config`.
+pub fn get_config() -> &'static Config {
+    static CONFIG: OnceCell<Config> = OnceCell::new();
+    CONFIG.get_or_init(|| read_config().unwrap_or_else(|errors| panic!("{}", errors)))
+}
+
+/// Read and parse the config the command line points to.
+fn read_config() -> Result<Config, ConfigErrors> {
+    let args = get_args();
+    let config_path = Path::new(args.config_path.as_deref().unwrap_or("quotebot.conf"));
+    let source = args
+        .config_format
+        .unwrap_or_else(|| ConfigSource::from_path(config_path));
+    // without an explicit path, a missing config file is fine as long as
+    // the environment provides everything
+    let config = load_config(config_path, source, args.config_path.is_some())
+        .map_err(|e| ConfigErrors(vec![format!("couldn't read config: {}", e)]))?;
+    parse_config(&config).map_err(ConfigErrors)
+}
+
+/// Every problem found with the config.
+#[derive(Debug)]
+pub struct ConfigErrors(Vec<String>);
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Config:\n  {}", self.0.join("\n  "))
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// The key a config error is about, which every message starts with.
+fn error_key(error: &str) -> Option<String> {
+    let key = error.split([' ', ':']).next()?;
+    (!key.is_empty()).then(|| key.to_owned())
+}
+
+/// Parse and validate the config, returning every problem rather than just
+/// the first.
+pub fn parse_config(config: &Layers) -> Result<Config, Vec<String>> {
+    let get_default = |key: &str| config.get("default", key);
+
+    // with no `servers` list, the only server is the default one at `titanic_url`
+    let default_server = config
+        .get("default", "default_server")
+        .unwrap_or("blacker".to_owned())
+        .to_lowercase();
+    let servers: Vec<TitanicServer> = match config.get("default", "servers") {
+        Some(names) => names
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .map(|name| TitanicServer {
+                url: config.require(
+                    config.get(&name, "url"),
+                    &format!("[{}] must specify url", name),
+                ),
+                name,
+            })
+            .collect(),
+        None => vec![TitanicServer {
+            name: default_server.clone(),
+            url: config
+                .get("default", "titanic_url")
+                .unwrap_or("https://blacker.caltech.edu/quotes/".to_owned()),
+        }],
+    };
+    if !servers.iter().any(|server| server.name == default_server) {
+        config.error("default_server must be one of servers".to_owned());
+    }
+
+    let mut deprecations = Vec::new();
+    let mut parsed = Config {
+        // token_file wins, so a mounted secret can override a token left in the file
+        token: match get_default("token_file") {
+            Some(path) => config
+                .check(
+                    std::fs::read_to_string(&path),
+                    &format!("token_file {} couldn't be read", path),
+                )
+                .trim()
+                .to_owned(),
+            None => config.require(
+                get_default("token"),
+                "token or token_file must be specified",
+            ),
+        },
+        quotes_channel_ids: config
+            .require(
+                get_default("quotes_channel_id"),
+                "quotes_channel_id must be specified",
+            )
+            .split(',')
+            .filter(|id| !id.trim().is_empty())
+            .filter_map(|id| {
+                config.check(
+                    id.trim().parse::<u64>().map(Some),
+                    "quotes_channel_id must be a list of channel ids",
+                )
+            })
+            .map(ChannelId::from)
+            .collect(),
+        db_path: config.require(get_default("db_file"), "db_file must be specified"),
+        quotes_db_path: config.require(
+            get_default("quotes_db_path"),
+            "quotes_db_path must be specified",
+        ),
+        credentials_key: config.require(
+            get_default("credentials_key"),
+            "credentials_key must be specified",
+        ),
+        poll_interval: Duration::from_millis(
+            config
+                .check(
+                    config.getuint("default", "poll_interval_ms"),
+                    "poll_interval_ms must be u64",
+                )
+                .filter(|&ms| ms > 0)
+                .unwrap_or(2500),
+        ),
+        admin_ids: config
+            .get("default", "admin_ids")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|id| !id.trim().is_empty())
+            .filter_map(|id| {
+                config.check(
+                    id.trim().parse::<u64>().map(Some),
+                    "admin_ids must be a list of user ids",
+                )
+            })
+            .map(UserId::from)
+            .collect(),
+        log_dir: config.get("default", "log_dir"),
+        log_level: config
+            .get("default", "log_level")
+            .unwrap_or("info".to_owned()),
+        prefix: config.get("default", "prefix").unwrap_or("~".to_owned()),
+        webhook_urls: config
+            .get("webhooks", "urls")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        tag_colors: config
+            .section("tag_colors")
+            .into_iter()
+            .flatten()
+            .filter_map(|(tag, color)| {
+                let color = parse_color(&color);
+                if color.is_none() {
+                    config.error(format!("tag_colors.{} must be a hex color", tag));
+                }
+                Some((tag.to_lowercase(), color?))
+            })
+            .collect(),
+        default_color: config
+            .get("default", "default_color")
+            .and_then(|color| {
+                let color = parse_color(&color);
+                if color.is_none() {
+                    config.error("default_color must be a hex color".to_owned());
+                }
+                color
+            })
+            .unwrap_or(0),
+        servers,
+        default_server,
+        notify_role_id: config
+            .check(
+                config.getuint("default", "notify_role_id"),
+                "notify_role_id must be u64",
+            )
+            .map(RoleId::from),
+        db_pool_size: config
+            .check(
+                config.getuint("default", "db_pool_size"),
+                "db_pool_size must be u64",
+            )
+            .map(|size| config.check(size.try_into(), "db_pool_size must fit in u32"))
+            .filter(|&size| size > 0)
+            .unwrap_or(4),
+        rate_limits: {
+            let mut per_command: HashMap<String, u32> = config
+                .section("rate_limits")
+                .into_iter()
+                .flatten()
+                .filter_map(|(key, limit)| {
+                    let Some(command) = key.strip_suffix("_per_minute") else {
+                        config.error(format!("rate_limits.{} must end in _per_minute", key));
+                        return None;
+                    };
+                    let limit = config.check(
+                        limit.trim().parse().map(Some),
+                        &format!("rate_limits.{} must be a u32", key),
+                    )?;
+                    Some((command.to_owned(), limit))
+                })
+                .collect();
+            // max_commands_per_minute predates [rate_limits]
+            let global_per_minute = per_command.remove("global").unwrap_or_else(|| {
+                config
+                    .check(
+                        config.getuint("default", "max_commands_per_minute"),
+                        "max_commands_per_minute must be u64",
+                    )
+                    .map(|limit| {
+                        config.check(limit.try_into(), "max_commands_per_minute must fit in u32")
+                    })
+                    .unwrap_or(20)
+            });
+            // quote_limit per quote_limit_window_secs predates [rate_limits]
+            // too, and becomes quote_per_minute, rounding up
+            let quote_limit = config.check(
+                config.getuint("default", "quote_limit"),
+                "quote_limit must be u64",
+            );
+            let quote_limit_window = config.check(
+                config.getuint("default", "quote_limit_window_secs"),
+                "quote_limit_window_secs must be u64",
+            );
+            if quote_limit.is_some() || quote_limit_window.is_some() {
+                deprecations.push(
+                    "quote_limit and quote_limit_window_secs are deprecated, \
+                     use quote_per_minute in [rate_limits]"
+                        .to_owned(),
+                );
+            }
+            per_command.entry("quote".to_owned()).or_insert_with(|| {
+                let limit = quote_limit.unwrap_or(3);
+                let window = quote_limit_window.unwrap_or(60).max(1);
+                config.check(
+                    (limit * 60).div_ceil(window).try_into(),
+                    "quote_limit must fit in u32",
+                )
+            });
+            RateLimits {
+                global_per_minute,
+                per_command,
+            }
+        },
+        shutdown_timeout: Duration::from_secs(
+            config
+                .check(
+                    config.getuint("default", "shutdown_timeout_secs"),
+                    "shutdown_timeout_secs must be u64",
+                )
+                .unwrap_or(10),
+        ),
+        command_permissions: config
+            .section("permissions")
+            .into_iter()
+            .flatten()
+            .map(|(command, ids)| {
+                let ids = ids
+                    .split(',')
+                    .filter(|id| !id.trim().is_empty())
+                    .filter_map(|id| {
+                        config.check(
+                            id.trim().parse::<u64>().map(Some),
+                            &format!("permissions.{} must be a list of ids", command),
+                        )
+                    })
+                    .collect();
+                (command, ids)
+            })
+            .collect(),
+        metrics_bind_addr: config.get("metrics", "bind_addr").and_then(|addr| {
+            config.check(
+                addr.parse().map(Some),
+                "metrics.bind_addr must be an address like 127.0.0.1:9100",
+            )
+        }),
+        message_prefix: config
+            .get("default", "message_prefix")
+            .filter(|prefix| !prefix.trim().is_empty()),
+        presence: config.section("discord").map(|_| Presence {
+            status_text: config.get("discord", "status_text"),
+            activity_type: config
+                .get("discord", "activity_type")
+                .map(|kind| match kind.to_lowercase().as_str() {
+                    "playing" => ActivityType::Playing,
+                    "watching" => ActivityType::Watching,
+                    "listening" => ActivityType::Listening,
+                    _ => {
+                        config.error(
+                            "discord.activity_type must be playing, watching or listening"
+                                .to_owned(),
+                        );
+                        ActivityType::Playing
+                    }
+                })
+                .unwrap_or(ActivityType::Playing),
+            status: config
+                .get("discord", "status")
+                .map(|status| match status.to_lowercase().as_str() {
+                    "online" => OnlineStatus::Online,
+                    "idle" => OnlineStatus::Idle,
+                    "dnd" => OnlineStatus::DoNotDisturb,
+                    _ => {
+                        config.error("discord.status must be online, idle or dnd".to_owned());
+                        OnlineStatus::Online
+                    }
+                })
+                .unwrap_or(OnlineStatus::Online),
+        }),
+        ignore_tags: config
+            .get("default", "ignore_tags")
+            .unwrap_or_default()
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("tag:").to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+        replay_delay: Duration::from_millis(
+            config
+                .check(
+                    config.getuint("default", "replay_delay_ms"),
+                    "replay_delay_ms must be u64",
+                )
+                .unwrap_or(1000),
+        ),
+        stall_timeout: Duration::from_secs(
+            config
+                .check(
+                    config.getuint("default", "stall_timeout_secs"),
+                    "stall_timeout_secs must be u64",
+                )
+                .unwrap_or(60),
+        ),
+        admin_discord_id: config
+            .check(
+                config.getuint("default", "admin_discord_id"),
+                "admin_discord_id must be u64",
+            )
+            .map(UserId::from),
+        feedback_channel_id: config
+            .check(
+                config.getuint("default", "feedback_channel_id"),
+                "feedback_channel_id must be u64",
+            )
+            .map(ChannelId::from),
+        discord_send_interval: {
+            let rate: f64 = config
+                .get("default", "discord_send_rate")
+                .and_then(|rate| {
+                    config.check(
+                        rate.trim().parse().map(Some),
+                        "discord_send_rate must be a number",
+                    )
+                })
+                .unwrap_or(1.0);
+            if rate > 0.0 {
+                Duration::from_secs_f64(1.0 / rate)
+            } else {
+                config.error("discord_send_rate must be positive".to_owned());
+                Duration::from_secs(1)
+            }
+        },
+        create_threads: config
+            .check(
+                config.getbool("default", "create_threads"),
+                "create_threads must be a boolean",
+            )
+            .unwrap_or(false),
+        daily_summary_time: config
+            .get("default", "daily_summary_time")
+            .and_then(|time| {
+                let parsed = time
+                    .trim()
+                    .split_once(':')
+                    .and_then(|(h, m)| Some((h.parse::<u64>().ok()?, m.parse::<u64>().ok()?)))
+                    .filter(|&(h, m)| h < 24 && m < 60);
+                if parsed.is_none() {
+                    config.error("daily_summary_time must be HH:MM".to_owned());
+                }
+                let (hours, minutes) = parsed?;
+                Some(Duration::from_secs(hours * 3600 + minutes * 60))
+            }),
+        cache_capacity: config
+            .check(
+                config.getuint("default", "cache_capacity"),
+                "cache_capacity must be u64",
+            )
+            .map(|capacity| config.check(capacity.try_into(), "cache_capacity must fit in usize"))
+            .unwrap_or(200),
+        quote_min_length: config
+            .check(
+                config.getuint("default", "quote_min_length"),
+                "quote_min_length must be u64",
+            )
+            .map(|length| config.check(length.try_into(), "quote_min_length must fit in usize"))
+            .unwrap_or(10),
+        quote_max_length: config
+            .check(
+                config.getuint("default", "quote_max_length"),
+                "quote_max_length must be u64",
+            )
+            .map(|length| config.check(length.try_into(), "quote_max_length must fit in usize"))
+            .unwrap_or(2000),
+        debounce: Duration::from_millis(
+            config
+                .check(
+                    config.getuint("default", "debounce_ms"),
+                    "debounce_ms must be u64",
+                )
+                .unwrap_or(50),
+        ),
+        embed_thumbnail_url: config
+            .get("default", "embed_thumbnail_url")
+            .and_then(|url| {
+                config.check(
+                    reqwest::Url::parse(url.trim()).map(|url| Some(url.into())),
+                    "embed_thumbnail_url must be a valid url",
+                )
+            }),
+        poll_stats_capacity: config
+            .check(
+                config.getuint("default", "poll_stats_capacity"),
+                "poll_stats_capacity must be u64",
+            )
+            .map(|capacity| {
+                config.check(capacity.try_into(), "poll_stats_capacity must fit in usize")
+            })
+            .unwrap_or(100),
+        auto_delete_credentials: config
+            .check(
+                config.getbool("default", "auto_delete_credentials"),
+                "auto_delete_credentials must be a boolean",
+            )
+            .unwrap_or(false),
+        notify_deleted_credentials: config
+            .check(
+                config.getbool("default", "notify_deleted_credentials"),
+                "notify_deleted_credentials must be a boolean",
+            )
+            .unwrap_or(false),
+        guild_allowlist: config
+            .get("default", "guild_allowlist")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|id| !id.trim().is_empty())
+            .filter_map(|id| {
+                config.check(
+                    id.trim().parse::<u64>().map(Some),
+                    "guild_allowlist must be a list of server ids",
+                )
+            })
+            .map(GuildId::from)
+            .collect(),
+        deprecations,
+    };
+    // announcements go out to everyone, so they're admin only unless
+    // [permissions] says otherwise
+    parsed
+        .command_permissions
+        .entry("announce".to_owned())
+        .or_insert_with(|| parsed.admin_ids.iter().map(|id| id.0).collect());
+    let mut errors = config.errors.take();
+    // A value that failed to parse was replaced by a default, so don't also
+    // report whatever validate() makes of that default.
+    let reported: Vec<String> = errors.iter().filter_map(|e| error_key(e)).collect();
+    errors.extend(
+        parsed
+            .validate()
+            .into_iter()
+            .filter(|e| error_key(e).is_none_or(|key| !reported.contains(&key))),
+    );
+    if errors.is_empty() {
+        Ok(parsed)
+    } else {
+        Err(errors)
+    }
+}
+
+impl Config {
+    /// Check values that parse but can't work, returning every problem
+    /// rather than just the first.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.token.trim().is_empty() {
+            errors.push("token must not be empty".to_owned());
+        }
+        if self.quotes_channel_ids.is_empty() {
+            errors.push("quotes_channel_id must list at least one channel".to_owned());
+        }
+        if self.quotes_channel_ids.iter().any(|id| id.0 == 0) {
+            errors.push("quotes_channel_id must not be 0".to_owned());
+        }
+        if let Err(e) = CredentialCipher::from_hex_key(&self.credentials_key) {
+            errors.push(format!("credentials_key must be 64 hex chars: {}", e));
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log_level) {
+            errors.push(format!("log_level must be a valid filter: {}", e));
+        }
+
+        let db_path = Path::new(&self.db_path);
+        if db_path.exists() {
+            if let Err(e) = OpenOptions::new().write(true).open(db_path) {
+                errors.push(format!("db_file {} isn't writable: {}", self.db_path, e));
+            }
+        } else {
+            let dir = db_path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            // the sqlite db is created on first run, so make sure we can
+            // create a file next to where it'll be
+            let probe = dir.join(format!(".quotebot-write-test-{}", std::process::id()));
+            match OpenOptions::new().write(true).create_new(true).open(&probe) {
+                Ok(_) => {
+                    let _ = std::fs::remove_file(&probe);
+                }
+                Err(e) => errors.push(format!(
+                    "db_file directory {} isn't writable: {}",
+                    dir.display(),
+                    e
+                )),
+            }
+        }
+        if let Err(e) = File::open(&self.quotes_db_path) {
+            errors.push(format!(
+                "quotes_db_path {} isn't readable: {}",
+                self.quotes_db_path, e
+            ));
+        }
+
+        for server in &self.servers {
+            if let Err(e) = reqwest::Url::parse(&server.url) {
+                errors.push(format!(
+                    "[{}] url {:?} isn't a valid url: {}",
+                    server.name, server.url, e
+                ));
+            }
+        }
+        for url in &self.webhook_urls {
+            if let Err(e) = reqwest::Url::parse(url) {
+                errors.push(format!("webhook url {:?} isn't a valid url: {}", url, e));
+            }
+        }
+
+        if self.stall_timeout <= self.poll_interval {
+            errors.push(
+                "stall_timeout_secs must be longer than poll_interval_ms, or every check stalls"
+                    .to_owned(),
+            );
+        }
+        if self.quote_min_length > self.quote_max_length {
+            errors.push("quote_min_length must not be more than quote_max_length".to_owned());
+        }
+        errors
+    }
+}
+
+/// Validate the config for `--check-config`: everything `get_config` parses
+/// and validates, plus that the quotes db has a readable quotes table.
+pub fn check_config() -> ah::Result<()> {
+    let config = read_config()?;
+
+    rusqlite::Connection::open_with_flags(
+        &config.quotes_db_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )
+    .and_then(|conn| conn.query_row("SELECT COUNT(*) FROM quotes", [], |r| r.get::<_, i64>(0)))
+    .map_err(|e| ah::anyhow!("Couldn't read quotes from {}: {}", config.quotes_db_path, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layers(toml: &str) -> Layers {
+        Layers::new(
+            ::config::Config::builder()
+                .add_source(::config::File::from_str(toml, FileFormat::Toml))
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn announce_defaults_to_admins() {
+        let dir = tempfile::tempdir().unwrap();
+        let quotes_db = dir.path().join("quotes.db");
+        rusqlite::Connection::open(&quotes_db)
+            .unwrap()
+            .execute(
+                "CREATE TABLE quotes (id INTEGER PRIMARY KEY, quote TEXT)",
+                [],
+            )
+            .unwrap();
+        let base = format!(
+            r#"
+            token = "token"
+            quotes_channel_id = "1"
+            db_file = "{}"
+            quotes_db_path = "{}"
+            credentials_key = "{}"
+            admin_ids = "5"
+            "#,
+            dir.path().join("bot.db").display(),
+            quotes_db.display(),
+            "00".repeat(32),
+        );
+        let config = parse_config(&layers(&base)).unwrap();
+        assert_eq!(config.command_permissions["announce"], [5]);
+
+        let config = parse_config(&layers(&format!(
+            "{}\n[permissions]\nannounce = \"7\"",
+            base
+        )))
+        .unwrap();
+        assert_eq!(config.command_permissions["announce"], [7]);
+    }
+
+    #[test]
+    fn every_bad_value_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let errors = parse_config(&layers(&format!(
+            r#"
+            quotes_channel_id = "1, x"
+            db_file = "{}"
+            quotes_db_path = "{}"
+            credentials_key = "abc"
+            log_level = "bot=loud"
+            poll_interval_ms = "abc"
+            default_color = "zz"
+            daily_summary_time = "25:00"
+            "#,
+            dir.path().join("bot.db").display(),
+            dir.path().join("missing.db").display(),
+        )))
+        .err()
+        .unwrap();
+        for key in [
+            "token",
+            "quotes_channel_id",
+            "poll_interval_ms",
+            "default_color",
+            "daily_summary_time",
+            "quotes_db_path",
+            "credentials_key",
+            "log_level",
+        ] {
+            assert!(
+                errors.iter().any(|e| e.starts_with(key)),
+                "{} not reported in {:?}",
+                key,
+                errors
+            );
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use anyhow as ah;
+use once_cell::sync::OnceCell;
+
+use crate::get_config;
+
+/// Encrypts titanic passwords before they hit the credentials table.
+///
+/// Stored values are hex encoded `nonce || ciphertext`, so they still fit in
+/// the existing TEXT column.
+pub struct CredentialCipher {
+    cipher: Aes256Gcm,
+}
+
+impl CredentialCipher {
+    /// Build a cipher from a 32 byte key given as 64 hex characters.
+    pub fn from_hex_key(key: &str) -> ah::Result<Self> {
+        let key = hex::decode(key.trim())?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| ah::anyhow!("key must be 32 bytes (64 hex chars)"))?;
+        Ok(Self { cipher })
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> ah::Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut stored = nonce.to_vec();
+        stored.extend(
+            self.cipher
+                .encrypt(&nonce, plaintext.as_bytes())
+                .map_err(|_| ah::anyhow!("Couldn't encrypt credentials"))?,
+        );
+        Ok(hex::encode(stored))
+    }
+
+    pub fn decrypt(&self, stored: &str) -> ah::Result<String> {
+        const NONCE_LEN: usize = 12;
+        let stored = hex::decode(stored)?;
+        if stored.len() < NONCE_LEN {
+            ah::bail!("Stored credentials are corrupt");
+        }
+        let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| ah::anyhow!("Couldn't decrypt stored credentials"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+pub fn get_cipher() -> &'static CredentialCipher {
+    static CIPHER: OnceCell<CredentialCipher> = OnceCell::new();
+    CIPHER.get_or_init(|| {
+        CredentialCipher::from_hex_key(&get_config().credentials_key)
+            .expect("Config: credentials_key must be 64 hex chars")
+    })
+}
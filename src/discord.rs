@@ -0,0 +1,124 @@
+use anyhow as ah;
+use async_trait::async_trait;
+use poise::serenity_prelude as serenity;
+use tracing::warn;
+
+use crate::{get_config, record_sent_message, send_with_retry, truncate_str, Quote};
+
+/// Where quotes get sent, so that sending can be tested without Discord.
+#[async_trait]
+pub trait DiscordHttp: Send + Sync {
+    async fn send_quote_embed(&self, channel: u64, quote: &Quote) -> ah::Result<()>;
+    async fn send_dm(&self, user: u64, content: &str) -> ah::Result<()>;
+}
+
+#[async_trait]
+impl DiscordHttp for serenity::Http {
+    async fn send_quote_embed(&self, channel: u64, quote: &Quote) -> ah::Result<()> {
+        let channel = serenity::ChannelId(channel);
+        let message = send_with_retry(channel, quote, self).await?;
+        let thread = if get_config().create_threads {
+            create_thread(self, &message, quote).await
+        } else {
+            None
+        };
+        if let Err(e) = record_sent_message(quote.id, &message, thread) {
+            warn!(id = quote.id, %channel, "Couldn't record sent message: {}", e);
+        }
+        Ok(())
+    }
+
+    async fn send_dm(&self, user: u64, content: &str) -> ah::Result<()> {
+        serenity::UserId(user)
+            .create_dm_channel(self)
+            .await?
+            .say(self, content)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Prints quotes instead of sending them, for `--noop-discord`.
+pub struct StdoutDiscord;
+
+#[async_trait]
+impl DiscordHttp for StdoutDiscord {
+    async fn send_quote_embed(&self, channel: u64, quote: &Quote) -> ah::Result<()> {
+        let mut embed = serenity::CreateEmbed::default();
+        crate::quote_embed(quote, get_config().message_prefix.as_deref(), &mut embed);
+        let field = |value: Option<&serde_json::Value>| {
+            value
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_owned()
+        };
+        println!(
+            "--- channel {} ---\n{}\n{}\n{}\n",
+            channel,
+            field(embed.0.get("title")),
+            field(embed.0.get("description")),
+            field(embed.0.get("footer").and_then(|footer| footer.get("text")))
+        );
+        Ok(())
+    }
+
+    async fn send_dm(&self, user: u64, content: &str) -> ah::Result<()> {
+        println!("--- DM to user {} ---\n{}\n", user, content);
+        Ok(())
+    }
+}
+
+/// Start a thread on a sent quote, named after its start. Failing, e.g.
+/// without the Create Public Threads permission, only loses the thread.
+async fn create_thread(
+    http: &serenity::Http,
+    message: &serenity::Message,
+    quote: &Quote,
+) -> Option<serenity::ChannelId> {
+    let name = truncate_str(quote.text.trim(), 50).replace('\n', " ");
+    let name = if name.trim().is_empty() {
+        format!("Quote #{}", quote.id)
+    } else {
+        name
+    };
+    match message
+        .channel_id
+        .create_public_thread(http, message.id, |thread| thread.name(name))
+        .await
+    {
+        Ok(thread) => Some(thread.id),
+        Err(e) => {
+            warn!(id = quote.id, channel = %message.channel_id, "Couldn't create thread: {}", e);
+            None
+        }
+    }
+}
+
+/// Records the embeds and DMs it's asked to send instead of sending them.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockHttp {
+    pub sent: std::sync::Mutex<Vec<(u64, serenity::CreateEmbed)>>,
+    pub dms: std::sync::Mutex<Vec<(u64, String)>>,
+    /// Channels that sends fail in.
+    pub failing: Vec<u64>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl DiscordHttp for MockHttp {
+    async fn send_quote_embed(&self, channel: u64, quote: &Quote) -> ah::Result<()> {
+        if self.failing.contains(&channel) {
+            ah::bail!("channel {} is failing", channel);
+        }
+        let mut embed = serenity::CreateEmbed::default();
+        crate::quote_embed(quote, get_config().message_prefix.as_deref(), &mut embed);
+        self.sent.lock().unwrap().push((channel, embed));
+        Ok(())
+    }
+
+    async fn send_dm(&self, user: u64, content: &str) -> ah::Result<()> {
+        self.dms.lock().unwrap().push((user, content.to_owned()));
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow as ah;
+use rusqlite as sql;
+use std::{fs::File, io::Write, str::FromStr};
+
+use crate::{get_config, watcher::QuoteSchema};
+
+/// File formats `--export` can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!(
+                "unknown export format {:?}, expected json or csv",
+                s
+            )),
+        }
+    }
+}
+
+struct Submission {
+    discord_id: u64,
+    auth_user: String,
+    quote_id: i64,
+    text: String,
+    tags: Option<String>,
+}
+
+/// Write every quote submitted by a registered user to `path`, without
+/// passwords. Quotes are matched to users by the quotes db's `author` column,
+/// against their credentials for the default server, whose db that is.
+pub fn export(path: &str, format: ExportFormat) -> ah::Result<usize> {
+    let config = get_config();
+    let conn =
+        sql::Connection::open_with_flags(&config.db_path, sql::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let ro_uri = format!("file:{}?mode=ro", config.quotes_db_path);
+    conn.execute("ATTACH DATABASE ?1 as quotes", [ro_uri])?;
+    let table = QuoteSchema::detect(&conn)?
+        .table_with_author()
+        .ok_or_else(|| {
+            ah::anyhow!(
+                "The quotes database has no author column, so quotes can't be matched to users"
+            )
+        })?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT c.discord_id, c.auth_user, q.id, q.quote, q.tags
+         FROM credentials c JOIN {} q ON q.author = c.auth_user
+         WHERE c.server = ?1
+         ORDER BY q.id",
+        table
+    ))?;
+    let submissions = stmt
+        .query_map([&config.default_server], |r| {
+            Ok(Submission {
+                discord_id: r.get(0)?,
+                auth_user: r.get(1)?,
+                quote_id: r.get(2)?,
+                text: r.get(3)?,
+                tags: r.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut file = File::create(path)?;
+    match format {
+        ExportFormat::Json => {
+            let records: Vec<_> = submissions
+                .iter()
+                .map(|s| {
+                    serde_json::json!({
+                        "discord_id": s.discord_id,
+                        "auth_user": s.auth_user,
+                        "quote_id": s.quote_id,
+                        "text": s.text,
+                        "tags": s.tags,
+                    })
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut file, &records)?;
+        }
+        ExportFormat::Csv => {
+            writeln!(file, "discord_id,auth_user,quote_id,text,tags")?;
+            for s in &submissions {
+                writeln!(
+                    file,
+                    "{},{},{},{},{}",
+                    s.discord_id,
+                    csv_field(&s.auth_user),
+                    s.quote_id,
+                    csv_field(&s.text),
+                    csv_field(s.tags.as_deref().unwrap_or(""))
+                )?;
+            }
+        }
+    }
+    Ok(submissions.len())
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
@@ -1,203 +1,787 @@
 use anyhow as ah;
 use argh::FromArgs;
 use async_shutdown::Shutdown;
-use once_cell::sync::OnceCell;
+use dashmap::DashMap;
+use once_cell::sync::{Lazy, OnceCell};
 use poise::{
-    serenity_prelude::{self as serenity, ChannelId},
+    serenity_prelude::{self as serenity, Mentionable},
     FrameworkError,
 };
+use r2d2_sqlite::SqliteConnectionManager;
+use rand::{seq::SliceRandom, Rng};
+use regex::Regex;
 use rusqlite as sql;
+use serde::{Deserialize, Serialize};
 use sql::OptionalExtension;
+use std::{
+    any::Any,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{prelude::*, EnvFilter};
 
+mod breaker;
+mod cache;
+mod config;
+mod crypto;
+mod discord;
+mod export;
+mod metrics;
+mod migrations;
+mod shutdown;
 mod watcher;
 
+use breaker::CircuitBreakerState;
+use cache::QuoteCache;
+use config::{get_config, ConfigSource, TitanicServer};
+use discord::DiscordHttp;
+use export::ExportFormat;
+use metrics::METRICS;
+use shutdown::ShutdownBarrier;
+use watcher::{QuoteSchema, WatcherRequest};
+
 #[derive(FromArgs)]
 /// Reach new heights.
 struct Args {
+    /// print the version and exit
+    #[argh(switch)]
+    version: bool,
     /// path to config. Defaults to the exe_dir/quotebot.conf
     #[argh(option)]
     config_path: Option<String>,
+    /// config format: ini, toml or json. Defaults to guessing from the extension
+    #[argh(option)]
+    config_format: Option<ConfigSource>,
+    /// print quotes instead of sending them to discord or titanic
+    #[argh(switch)]
+    dry_run: bool,
+    /// apply any pending database migrations and exit
+    #[argh(switch)]
+    migrate: bool,
+    /// validate the config and databases, then exit
+    #[argh(switch)]
+    check_config: bool,
+    /// log as JSON, as does setting LOG_FORMAT=json
+    #[argh(switch)]
+    json_logs: bool,
+    /// watch for quotes but print them instead of connecting to Discord
+    #[argh(switch)]
+    noop_discord: bool,
+    /// print the config as it was loaded, without secrets, and exit
+    #[argh(switch)]
+    dump_config: bool,
+    /// print the slash commands as a markdown table and exit
+    #[argh(switch)]
+    list_commands: bool,
+    /// send any new quotes once and exit, for running from a timer
+    #[argh(switch)]
+    once: bool,
+    /// send every quote with an id greater than this and exit
+    #[argh(option)]
+    replay_since: Option<i64>,
+    /// write quotes submitted by registered users to this file and exit
+    #[argh(option)]
+    export: Option<String>,
+    /// export format: json or csv. Defaults to json
+    #[argh(option, default = "ExportFormat::Json")]
+    export_format: ExportFormat,
 }
 
-struct Config {
-    token: String,
-    db_path: String,
-    quotes_channel_id: u64,
-    quotes_db_path: String,
-}
-
-fn get_config() -> &'static Config {
-    static CONFIG: OnceCell<Config> = OnceCell::new();
-    CONFIG.get_or_init(|| {
-        let args: Args = argh::from_env();
-        let config_path = args.config_path.unwrap_or("quotebot.conf".to_owned());
-        let mut config = configparser::ini::Ini::new();
-        config.load(config_path).expect("Couldn't read config file");
-
-        Config {
-            token: config
-                .get("default", "token")
-                .expect("Config: token must be specified."),
-            quotes_channel_id: config
-                .getuint("default", "quotes_channel_id")
-                .expect("channel id must be u64")
-                .expect("Config: quotes_channel_id required"),
-            db_path: config
-                .get("default", "db_file")
-                .expect("Config: db_file must be specified"),
-            quotes_db_path: config
-                .get("default", "quotes_db_path")
-                .expect("Config: quotes_db_path must be specified."),
-        }
-    })
+fn get_args() -> &'static Args {
+    static ARGS: OnceCell<Args> = OnceCell::new();
+    // the test harness's arguments aren't ours
+    if cfg!(test) {
+        return ARGS.get_or_init(|| Args::from_args(&["hellquotes-bot"], &[]).unwrap());
+    }
+    ARGS.get_or_init(argh::from_env)
 }
 
-fn get_db() -> ah::Result<sql::Connection> {
-    let path = &get_config().db_path;
-    let conn = sql::Connection::open(path)?;
+type DbConnection = r2d2::PooledConnection<SqliteConnectionManager>;
 
-    static DB_INIT: OnceCell<()> = OnceCell::new();
-    DB_INIT.get_or_try_init(|| {
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS credentials (
-                   discord_id                INTEGER PRIMARY KEY,
-                   auth_user                 TEXT,
-                   auth_pass                 TEXT
-                   )",
-            [],
-        )
-        .and(Ok(()))
+fn get_db() -> ah::Result<DbConnection> {
+    static POOL: OnceCell<r2d2::Pool<SqliteConnectionManager>> = OnceCell::new();
+    let pool = POOL.get_or_try_init(|| -> ah::Result<_> {
+        let config = get_config();
+        let pool = r2d2::Pool::builder().max_size(config.db_pool_size).build(
+            // WAL lets readers carry on while another connection writes,
+            // and with it NORMAL sync is still safe from corruption, so
+            // pooled connections rarely wait on each other
+            SqliteConnectionManager::file(&config.db_path).with_init(|conn| {
+                conn.execute_batch("PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL;")
+            }),
+        )?;
+        init_db(&*pool.get()?)?;
+        Ok(pool)
     })?;
+    Ok(pool.get()?)
+}
+
+fn init_db(conn: &sql::Connection) -> sql::Result<()> {
+    migrations::run_migrations(conn, &get_config().default_server)?;
+    Ok(())
+}
+
+/// Open the bot database with the quotes database attached read-only as `quotes`.
+fn get_quotes_db() -> ah::Result<DbConnection> {
+    let conn = get_db()?;
+    watcher::attach_current(&conn, &get_config().quotes_db_path)?;
     Ok(conn)
 }
 
+/// The quote with id `id`, if there is one.
+fn get_quote(id: i64) -> ah::Result<Option<Quote>> {
+    let conn = get_quotes_db()?;
+    Ok(conn
+        .query_row(
+            &format!(
+                "SELECT id, quote, tags FROM {} WHERE id = ?1",
+                QuoteSchema::detect(&conn)?.table()
+            ),
+            [id],
+            Quote::from_row,
+        )
+        .optional()?)
+}
+
+/// Look up a configured titanic server by name, or the default one.
+fn get_server(name: Option<&str>) -> Result<&'static TitanicServer, Error> {
+    let config = get_config();
+    let name = name
+        .map(str::to_lowercase)
+        .unwrap_or_else(|| config.default_server.clone());
+    config
+        .servers
+        .iter()
+        .find(|server| server.name == name)
+        .ok_or_else(|| {
+            let names: Vec<&str> = config.servers.iter().map(|s| s.name.as_str()).collect();
+            BotError::user(format!(
+                "Unknown server \"{}\", expected one of: {}",
+                name,
+                names.join(", ")
+            ))
+        })
+}
+
+/// Base url of the default titanic server's quotes page. The watched quotes
+/// database belongs to this server.
+fn get_titanic_url() -> &'static str {
+    let config = get_config();
+    &config
+        .servers
+        .iter()
+        .find(|server| server.name == config.default_server)
+        .expect("default server is validated with the config")
+        .url
+}
+
+/// How long to wait on a quote submission, so a titanic that accepts
+/// connections but never answers counts as down.
+const TITANIC_TIMEOUT: Duration = Duration::from_secs(10);
+
 fn get_client() -> &'static reqwest::Client {
     static CLIENT: OnceCell<reqwest::Client> = OnceCell::new();
-    CLIENT.get_or_init(|| reqwest::Client::new())
+    CLIENT.get_or_init(reqwest::Client::new)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Quote {
     id: i64,
     text: String,
     tags: Option<String>,
 }
 
+impl Quote {
+    /// Build a quote from a row selected as `id, quote, tags`.
+    fn from_row(r: &sql::Row) -> sql::Result<Self> {
+        Ok(Quote {
+            id: r.get(0)?,
+            text: r.get(1)?,
+            tags: r.get(2)?,
+        })
+    }
+    /// The first of the quote's tags that's in the `ignore_tags` config.
+    fn ignored_tag(&self) -> Option<&str> {
+        let ignore_tags = &get_config().ignore_tags;
+        self.tags
+            .as_deref()?
+            .split_whitespace()
+            .find(|tag| ignore_tags.contains(&tag.to_lowercase()))
+    }
+
+    /// Link to the quote on the default titanic server.
+    fn url(&self) -> String {
+        self.url_on(get_titanic_url())
+    }
+
+    fn url_on(&self, titanic_url: &str) -> String {
+        format!("{}?q={}", titanic_url, self.id)
+    }
+}
+
+impl std::fmt::Display for Quote {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[#{}] \"{}\"", self.id, self.text)?;
+        match self.tags.as_deref() {
+            Some(tags) if !tags.is_empty() => write!(f, " (tags: {})", tags),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Data {
-    poll_tx: mpsc::Sender<()>,
+    poll_tx: mpsc::Sender<WatcherRequest>,
+    /// Per discord user: when each command in the last minute was run.
+    command_history: DashMap<serenity::UserId, VecDeque<Instant>>,
+    /// Per discord user and rate limited command: when it was run in the last minute.
+    per_command_history: DashMap<(serenity::UserId, String), VecDeque<Instant>>,
+    titanic_breaker: Mutex<CircuitBreakerState>,
+    quote_cache: Arc<QuoteCache>,
+}
+
+/// Errors returned from commands. User errors are shown to the user as-is,
+/// internal errors are logged and replaced with a generic message.
+#[derive(Debug)]
+enum BotError {
+    UserError(String),
+    InternalError(ah::Error),
+}
+
+impl BotError {
+    fn user(msg: impl Into<String>) -> Self {
+        BotError::UserError(msg.into())
+    }
+}
+
+impl std::fmt::Display for BotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotError::UserError(msg) => f.write_str(msg),
+            BotError::InternalError(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl<E: Into<ah::Error>> From<E> for BotError {
+    fn from(e: E) -> Self {
+        BotError::InternalError(e.into())
+    }
 }
-type Error = Box<dyn std::error::Error + Send + Sync>;
+
+type Error = BotError;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
 /// Register your titanic login so that you can send quotes from discord.
 #[poise::command(slash_command, dm_only)]
+#[tracing::instrument(skip(ctx, pass), fields(discord_id = %ctx.author().id))]
 async fn register(
     ctx: Context<'_>,
     #[description = "titanic user"] user: String,
     #[description = "titanic pass"] pass: String,
+    #[description = "titanic server, if not the default"] server: Option<String>,
 ) -> Result<(), Error> {
+    let server = get_server(server.as_deref())?;
     if !get_client()
-        .get("https://blacker.caltech.edu/quotes/")
+        .get(&server.url)
         .basic_auth(&user, Some(&pass))
         .send()
         .await?
         .status()
         .is_success()
     {
-        Err(ah::anyhow!("Credentials didn't work"))?
+        return Err(BotError::user("Credentials didn't work"));
     }
 
     let discord_user_id = ctx.author().id.as_u64();
     let conn = get_db()?;
     conn.execute(
-        "INSERT OR REPLACE INTO credentials (discord_id, auth_user, auth_pass) VALUES (?1, ?2, ?3
-  )",
-        sql::params![discord_user_id, user, pass],
+        "INSERT OR REPLACE INTO credentials (discord_id, server, auth_user, auth_pass, encrypted)
+         VALUES (?1, ?2, ?3, ?4, 1)",
+        sql::params![
+            discord_user_id,
+            server.name,
+            user,
+            crypto::get_cipher().encrypt(&pass)?
+        ],
     )?;
 
     poise::say_reply(
         ctx,
-        "Successfully updated your registration. You can now send quotes!",
+        format!(
+            "Successfully updated your registration on {}. You can now send quotes!",
+            server.name
+        ),
     )
     .await?;
 
     Ok(())
 }
 
+/// Delete your stored titanic login.
+#[poise::command(slash_command, dm_only)]
+async fn unregister(
+    ctx: Context<'_>,
+    #[description = "titanic server, if not all of them"] server: Option<String>,
+) -> Result<(), Error> {
+    let discord_user_id = ctx.author().id.as_u64();
+    let conn = get_db()?;
+    let deleted = match server {
+        Some(server) => conn.execute(
+            "DELETE FROM credentials WHERE discord_id = ?1 AND server = ?2",
+            sql::params![discord_user_id, get_server(Some(&server))?.name],
+        )?,
+        None => conn.execute(
+            "DELETE FROM credentials WHERE discord_id = ?1",
+            [discord_user_id],
+        )?,
+    };
+
+    let reply = if deleted == 0 {
+        "You weren't registered, so there was nothing to delete."
+    } else {
+        "Your credentials have been deleted."
+    };
+    poise::say_reply(ctx, reply).await?;
+
+    Ok(())
+}
+
+/// Check whether you're registered, and as whom.
+#[poise::command(slash_command, dm_only)]
+async fn whoami(ctx: Context<'_>) -> Result<(), Error> {
+    let discord_user_id = ctx.author().id.as_u64();
+    let conn = get_db()?;
+    let registrations = {
+        let mut stmt = conn.prepare(
+            "SELECT server, auth_user FROM credentials WHERE discord_id = ?1 ORDER BY server",
+        )?;
+        let registrations = stmt
+            .query_map([discord_user_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        registrations
+    };
+
+    let status = if registrations.is_empty() {
+        "You are not registered.".to_owned()
+    } else {
+        registrations
+            .iter()
+            .map(|(server, user)| format!("You are registered as `{}` on {}", user, server))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    ctx.send(|reply| {
+        reply
+            .embed(|embed| embed.description(status))
+            .ephemeral(true)
+    })
+    .await?;
+
+    Ok(())
+}
+
 fn quote_help() -> String {
-    String::from(
+    format!(
         "\
-Accessible via /quote or ~quote, in the server or in DMs. ~quote will show to
+Accessible via /quote or {p}quote, in the server or in DMs. {p}quote will show to
 other people in the server. Usually, people don't see who submits hellquotes, so
-consider using /quote or ~quote in DMs. ~quote is the only way to write multiple
+consider using /quote or {p}quote in DMs. {p}quote is the only way to write multiple
 lines. To add tags, prefix tag:[tag] as many times as you want, separated by
-spaces.
+spaces. Tags may only contain letters, numbers, hyphens and underscores. To
+quote to a server other than the default, prefix server:[name]. {p}q is short
+for {p}quote.
 
 Example usage:
-~quote tag:anon tag:blacker my awesome quote
+{p}quote tag:anon tag:blacker my awesome quote
 
 -anonymous",
+        p = get_config().prefix
     )
 }
 
+/// Reject tags that could confuse titanic's tag handling.
+fn validate_tag(tag: &str) -> Result<(), Error> {
+    static TAG_RE: Lazy<Regex> = Lazy::new(|| Regex::new("^[A-Za-z0-9_-]+$").unwrap());
+    if !TAG_RE.is_match(tag) {
+        return Err(BotError::user(format!(
+            "Invalid tag \"{}\": tags may only contain letters, numbers, hyphens and underscores.",
+            tag
+        )));
+    }
+    Ok(())
+}
+
+/// Reject quotes outside the configured length limits, before bothering titanic.
+fn validate_quote_length(quote: &str) -> Result<(), Error> {
+    let config = get_config();
+    let length = quote.chars().count();
+    if length < config.quote_min_length {
+        return Err(BotError::user(format!(
+            "Quotes must be at least {} characters long, that one is {}.",
+            config.quote_min_length, length
+        )));
+    }
+    if length > config.quote_max_length {
+        return Err(BotError::user(format!(
+            "Quotes can be at most {} characters long, that one is {}.",
+            config.quote_max_length, length
+        )));
+    }
+    Ok(())
+}
+
+/// Look up a user's titanic login, upgrading plaintext passwords on the way.
+fn get_credentials(discord_id: u64, server: &str) -> Result<(String, String), Error> {
+    let conn = get_db()?;
+    let (user, stored_pass, encrypted): (String, String, bool) = conn
+        .query_row(
+            "SELECT auth_user, auth_pass, encrypted FROM credentials
+             WHERE discord_id = ?1 AND server = ?2",
+            sql::params![discord_id, server],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?
+        .ok_or_else(|| {
+            BotError::user(format!(
+                "You aren't registered on {}, try DMing me the /register command",
+                server
+            ))
+        })?;
+    let pass = if encrypted {
+        crypto::get_cipher().decrypt(&stored_pass)?
+    } else {
+        conn.execute(
+            "UPDATE credentials SET auth_pass = ?1, encrypted = 1
+             WHERE discord_id = ?2 AND server = ?3",
+            sql::params![
+                crypto::get_cipher().encrypt(&stored_pass)?,
+                discord_id,
+                server
+            ],
+        )?;
+        stored_pass
+    };
+    Ok((user, pass))
+}
+
+/// Reject commands from servers not in a non-empty `guild_allowlist`. DMs
+/// are always allowed.
+async fn guild_allowed(ctx: Context<'_>) -> Result<bool, Error> {
+    let allowlist = &get_config().guild_allowlist;
+    match ctx.guild_id() {
+        Some(guild) if !allowlist.is_empty() && !allowlist.contains(&guild) => {
+            warn!(%guild, user = %ctx.author().id, "Command from a server not in guild_allowlist");
+            Err(BotError::user("This bot isn't available in this server."))
+        }
+        _ => Ok(true),
+    }
+}
+
+/// Restrict commands listed in the `[permissions]` config to the users and
+/// roles given there. Unlisted commands are open to everyone.
+async fn has_permission(ctx: Context<'_>) -> Result<bool, Error> {
+    let Some(allowed) = get_config()
+        .command_permissions
+        .get(&ctx.command().qualified_name)
+    else {
+        return Ok(true);
+    };
+    if allowed.contains(ctx.author().id.as_u64()) {
+        return Ok(true);
+    }
+    Ok(match ctx.author_member().await {
+        Some(member) => member
+            .roles
+            .iter()
+            .any(|role| allowed.contains(role.as_u64())),
+        // no roles in DMs
+        None => false,
+    })
+}
+
+/// Record a run in `history` if it has fewer than `limit` runs in the last
+/// minute, otherwise return how long until it will.
+fn check_per_minute(history: &mut VecDeque<Instant>, limit: u32) -> Result<(), Duration> {
+    const WINDOW: Duration = Duration::from_secs(60);
+    let now = Instant::now();
+    while history
+        .front()
+        .is_some_and(|&run| now.duration_since(run) >= WINDOW)
+    {
+        history.pop_front();
+    }
+    if history.len() >= limit as usize {
+        // a limit of 0 has no runs to wait out
+        return Err(history
+            .front()
+            .map_or(WINDOW, |&oldest| WINDOW - now.duration_since(oldest)));
+    }
+    history.push_back(now);
+    Ok(())
+}
+
+/// Apply the `[rate_limits]` config: every user gets `global_per_minute`
+/// commands of any kind, and commands with their own limit are limited
+/// separately as well.
+async fn rate_limit(ctx: Context<'_>) -> Result<bool, Error> {
+    let limits = &get_config().rate_limits;
+    let user = ctx.author().id;
+    let command = &ctx.command().name;
+    let result = {
+        let mut history = ctx.data().command_history.entry(user).or_default();
+        check_per_minute(&mut history, limits.global_per_minute)
+    }
+    .and_then(|()| match limits.per_command.get(command) {
+        Some(&limit) => {
+            let mut history = ctx
+                .data()
+                .per_command_history
+                .entry((user, command.clone()))
+                .or_default();
+            check_per_minute(&mut history, limit)
+        }
+        None => Ok(()),
+    });
+    if let Err(remaining) = result {
+        warn!(user = %user, %command, "User hit a command rate limit");
+        return Err(BotError::user(format!(
+            "You're using commands too fast, try again in {} seconds.",
+            remaining.as_secs() + 1
+        )));
+    }
+    Ok(true)
+}
+
+/// Split leading space-separated "tag:[tag]"s and "server:[name]" off of
+/// `text`, returning the tags joined by spaces, the server, and the rest.
+fn parse_prefixes(text: &str) -> Result<(String, Option<&str>, &str), Error> {
+    const TAG_PATTERN: &str = "tag:";
+    const SERVER_PATTERN: &str = "server:";
+    let mut tags = Vec::new();
+    let mut server = None;
+    let mut iter = text.split_whitespace().peekable();
+    while let Some(&word) = iter.peek() {
+        if let Some(tag) = word.strip_prefix(TAG_PATTERN) {
+            validate_tag(tag)?;
+            tags.push(tag);
+        } else if let Some(name) = word.strip_prefix(SERVER_PATTERN) {
+            server = Some(name);
+        } else {
+            break;
+        }
+        iter.next();
+    }
+
+    let rest = match iter.next() {
+        Some(start) => {
+            let start_index = start.as_ptr() as usize - text.as_ptr() as usize;
+            text[start_index..].trim_end()
+        }
+        None => "",
+    };
+    Ok((tags.join(" "), server, rest))
+}
+
+/// Send a request that changes something on titanic. In a dry run, print it
+/// instead and return `None`.
+async fn send_titanic_write(
+    request: reqwest::RequestBuilder,
+) -> reqwest::Result<Option<reqwest::Response>> {
+    if get_args().dry_run {
+        let request = request.build()?;
+        println!(
+            "[dry run] Would send {} {}",
+            request.method(),
+            request.url()
+        );
+        return Ok(None);
+    }
+    request.send().await.map(Some)
+}
+
+/// Tell the user a command did nothing because this is a dry run.
+async fn reply_dry_run(ctx: Context<'_>) -> Result<(), Error> {
+    poise::send_reply(ctx, |r| {
+        r.content("Dry run, nothing was changed.").ephemeral(true)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Turn an unsuccessful titanic response into an error for the user, with
+/// whatever titanic said about it.
+async fn check_titanic_response(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(BotError::user(format!(
+        "Hellquotes gave status: {}\n{}",
+        status,
+        truncate_str(body.trim(), 500)
+    )))
+}
+
 /// Send a quote. For multiple lines, use ~quote not /quote. For anonymity, use /quote or DMs.
-#[poise::command(slash_command, prefix_command, help_text_fn = "quote_help")]
+#[poise::command(
+    slash_command,
+    prefix_command,
+    aliases("q"),
+    help_text_fn = "quote_help"
+)]
+#[tracing::instrument(skip(ctx, text), fields(discord_id = %ctx.author().id))]
 async fn quote(
     ctx: Context<'_>,
-    #[description = "quote text, preceeded by zero or more space-separated \"tag:[tag]\"s"]
+    #[description = "quote text, preceeded by any space-separated \"tag:[tag]\"s and \"server:[name]\""]
     #[rest]
     text: String,
 ) -> Result<(), Error> {
-    let mut tag_string = String::new();
-    let mut iter = text.split_whitespace().peekable();
-    const PATTERN: &str = "tag:";
-    while let Some(tag) = iter
-        .peek()
-        .ok_or(ah::anyhow!(
-            "Message must have a non-empty, non-tag portion."
-        ))?
-        .strip_prefix(PATTERN)
-    {
-        tag_string.push_str(tag);
-        tag_string.push(' ');
-        iter.next();
+    let result = submit_quote(ctx, &text).await;
+    if !get_args().dry_run {
+        if let Err(e) = record_submission(*ctx.author().id.as_u64(), result.is_ok()) {
+            warn!("Couldn't record submission: {}", e);
+        }
     }
-    tag_string.pop();
+    result
+}
 
-    let quote = {
-        let quote_string_start_slice = iter.next().ok_or(ah::anyhow!(
-            "Message must have a non-empty, non-tag portion."
-        ))?;
-        let quote_string_start_index =
-            quote_string_start_slice.as_ptr() as usize - text.as_ptr() as usize;
-        text[quote_string_start_index..].trim_end()
+/// Keep a record of a `/quote` attempt, for auditing.
+fn record_submission(discord_id: u64, success: bool) -> ah::Result<()> {
+    get_db()?.execute(
+        "INSERT INTO submissions (discord_id, submitted_at, success)
+         VALUES (?1, datetime('now'), ?2)",
+        sql::params![discord_id, success],
+    )?;
+    Ok(())
+}
+
+/// Remember who submitted `quote`, so they can be told when it's posted.
+fn record_pending_submission(discord_id: u64, quote: &str) -> ah::Result<()> {
+    get_db()?.execute(
+        "INSERT INTO pending_submissions (discord_id, quote, submitted_at)
+         VALUES (?1, ?2, datetime('now'))",
+        sql::params![discord_id, quote],
+    )?;
+    Ok(())
+}
+
+/// DM whoever submitted a quote that's just been posted. Submissions that
+/// never showed up, e.g. because titanic dropped them, are forgotten after
+/// a day.
+async fn notify_submitter(quote: &Quote, http: &dyn DiscordHttp) {
+    if quote.ignored_tag().is_some() || get_args().dry_run {
+        return;
+    }
+    let submitter = get_db().and_then(|conn| {
+        conn.execute(
+            "DELETE FROM pending_submissions WHERE submitted_at < datetime('now', '-1 day')",
+            [],
+        )?;
+        Ok(conn
+            .query_row(
+                "DELETE FROM pending_submissions WHERE id = (
+                     SELECT id FROM pending_submissions WHERE quote = ?1 ORDER BY id LIMIT 1
+                 ) RETURNING discord_id",
+                [&quote.text],
+                |r| r.get::<_, u64>(0),
+            )
+            .optional()?)
+    });
+    let discord_id = match submitter {
+        Ok(Some(discord_id)) => discord_id,
+        Ok(None) => return,
+        Err(e) => {
+            warn!(id = quote.id, "Couldn't look up who submitted quote: {}", e);
+            return;
+        }
     };
+    let content = format!("Your quote #{} has been posted!", quote.id);
+    if let Err(e) = http.send_dm(discord_id, &content).await {
+        warn!(
+            id = quote.id,
+            discord_id, "Couldn't tell submitter their quote was posted: {}", e
+        );
+    }
+}
+
+async fn submit_quote(ctx: Context<'_>, text: &str) -> Result<(), Error> {
+    let (tag_string, server, quote) = parse_prefixes(text)?;
+    if quote.is_empty() {
+        return Err(BotError::user(
+            "Message must have a non-empty, non-tag portion.",
+        ));
+    }
+    validate_quote_length(quote)?;
 
     let discord_id = ctx.author().id.as_u64();
 
-    let conn = get_db()?;
-    let (user, pass): (String, String) = conn
-        .query_row(
-            "SELECT auth_user, auth_pass FROM credentials where discord_id = ?1",
-            [discord_id],
-            |row| Ok((row.get(0)?, row.get(1)?)),
-        )
-        .optional()?
-        .ok_or(ah::anyhow!(
-            "You aren't registered, try DMing me the /register command"
-        ))?;
+    let server = get_server(server)?;
+    let (user, pass) = get_credentials(*discord_id, &server.name)?;
+    info!(server = %server.name, titanic_user = %user, "Found credentials");
+
+    if get_args().dry_run {
+        println!(
+            "[dry run] Would submit quote as {}: {:?} (tags: {:?})",
+            user, quote, tag_string
+        );
+        poise::send_reply(ctx, |r| {
+            r.content("Dry run, not submitted.").ephemeral(true)
+        })
+        .await?;
+        return Ok(());
+    }
 
+    let breaker = &ctx.data().titanic_breaker;
+    let check = breaker.lock().unwrap().check();
+    if let Err(wait) = check {
+        return Err(BotError::user(format!(
+            "Hellquotes seems to be down, try again in {} seconds.",
+            wait.as_secs() + 1
+        )));
+    }
+    info!(url = %server.url, "Submitting quote to titanic");
     let response = get_client()
-        .post("https://blacker.caltech.edu/quotes/")
+        .post(&server.url)
+        .timeout(TITANIC_TIMEOUT)
         .basic_auth(user, Some(pass))
         .form(&[("quote", quote), ("tags", &tag_string)])
         .send()
-        .await?;
+        .await;
+    match &response {
+        Ok(response) => info!(status = %response.status(), "Titanic responded"),
+        Err(e) => warn!("Couldn't reach titanic: {}", e),
+    }
+    // only count outages, a rejected quote means titanic is up
+    let response = match response {
+        Ok(response) if !response.status().is_server_error() => {
+            breaker.lock().unwrap().record_success();
+            response
+        }
+        Ok(response) => {
+            breaker.lock().unwrap().record_failure();
+            response
+        }
+        Err(e) => {
+            breaker.lock().unwrap().record_failure();
+            return Err(e.into());
+        }
+    };
     if !response.status().is_success() {
-        return Err(ah::anyhow!("Hellquotes gave status: {}", response.status()).into());
+        return Err(BotError::user(format!(
+            "Hellquotes gave status: {}",
+            response.status()
+        )));
+    }
+    METRICS.quotes_submitted.inc();
+    if let Err(e) = record_pending_submission(*discord_id, quote) {
+        warn!("Couldn't record pending submission: {}", e);
     }
 
     // if this is a slash cmd, send an invisible reply so that we don't get a
@@ -209,11 +793,20 @@ async fn quote(
 
     // prompt quote watcher to check for the newly submitted quote so it shows
     // up faster
-    ctx.data().poll_tx.try_send(()).ok();
+    ctx.data().poll_tx.try_send(WatcherRequest::Poll).ok();
 
     Ok(())
 }
 
+/// The message a panic was raised with, if it had one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| payload.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic")
+}
+
 fn truncate_str(s: &str, max_chars: usize) -> &str {
     match s.char_indices().nth(max_chars) {
         None => s,
@@ -221,104 +814,1683 @@ fn truncate_str(s: &str, max_chars: usize) -> &str {
     }
 }
 
-async fn send_quote(quote: &Quote, http: &serenity::Http) -> ah::Result<()> {
-    info!(id = quote.id, "Submitting quote to discord");
-    // truncate quote text to ensure message is under 2000 chars
-    let text = truncate_str(&quote.text, 1600);
-    let tags = truncate_str(quote.tags.as_ref().map(String::as_str).unwrap_or(""), 200);
+/// Like `truncate_str`, but if the cut would leave a markdown span (bold,
+/// italics, underline or code) open, cut before the span instead. Markers
+/// that Discord renders literally, like the `_` in `snake_case` or the `*` in
+/// `5 * 3`, don't open spans, and a span opened more than `MAX_CUT_BACK`
+/// chars before the cut is left open rather than losing that much text.
+fn truncate_str_markdown_safe(s: &str, max_chars: usize) -> &str {
+    const MARKERS: [&str; 5] = ["**", "__", "*", "_", "`"];
+    const MAX_CUT_BACK: usize = 200;
+    let truncated = truncate_str(s, max_chars);
+    if truncated.len() == s.len() {
+        return s;
+    }
 
-    ChannelId::from(get_config().quotes_channel_id)
-        .send_message(http, |msg| {
-            msg.embed(|embed| {
-                embed
-                    .title(text)
-                    .description(format!(
-                        "[View on Titanic](https://blacker.caltech.edu/quotes/?q={})",
-                        quote.id
-                    ))
-                    .color(0)
-                    .footer(|footer| footer.text(format!("Tags: {}", tags)))
-            })
-        })
-        .await?;
-    Ok(())
+    // where each marker's unclosed span starts, if it has one
+    let mut open: [Option<usize>; MARKERS.len()] = [None; MARKERS.len()];
+    let mut idx = 0;
+    while idx < truncated.len() {
+        let rest = &truncated[idx..];
+        let in_code = open[MARKERS.len() - 1].is_some();
+        let marker = MARKERS
+            .iter()
+            .position(|marker| rest.starts_with(marker))
+            // nothing is markdown inside code but the closing backtick
+            .filter(|&i| !in_code || MARKERS[i] == "`");
+        let Some(i) = marker else {
+            idx += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+        let before = s[..idx].chars().next_back();
+        let after = s[idx + MARKERS[i].len()..].chars().next();
+        let literal = if MARKERS[i].starts_with('_') {
+            before.is_some_and(char::is_alphanumeric) && after.is_some_and(char::is_alphanumeric)
+        } else if MARKERS[i].starts_with('*') {
+            before.is_none_or(char::is_whitespace) && after.is_none_or(char::is_whitespace)
+        } else {
+            false
+        };
+        if !literal {
+            open[i] = match open[i] {
+                Some(_) => None,
+                None => Some(idx),
+            };
+        }
+        idx += MARKERS[i].len();
+    }
+    match open.iter().flatten().min() {
+        Some(&start) if truncated[start..].chars().count() <= MAX_CUT_BACK => &truncated[..start],
+        _ => truncated,
+    }
 }
 
-#[poise::command(prefix_command, slash_command)]
-async fn help(
-    ctx: Context<'_>,
-    #[description = "Specific command to show help about"] command: Option<String>,
-) -> Result<(), Error> {
-    let config = poise::builtins::HelpConfiguration {
-        extra_text_at_bottom: "Type /help command for more info on a command.",
-        ..Default::default()
+/// `prefix` goes on its own line before the quote text.
+fn quote_embed<'a>(
+    quote: &Quote,
+    prefix: Option<&str>,
+    embed: &'a mut serenity::CreateEmbed,
+) -> &'a mut serenity::CreateEmbed {
+    // the quote goes in the description, since titles don't render newlines
+    // and are capped at 256 chars. Descriptions are capped at 4096.
+    let description = match prefix {
+        Some(prefix) => {
+            let prefix = truncate_str(prefix, 200);
+            let text = truncate_str_markdown_safe(&quote.text, 4096 - prefix.chars().count() - 1);
+            format!("{}\n{}", prefix, text)
+        }
+        None => truncate_str_markdown_safe(&quote.text, 4096).to_owned(),
     };
-    poise::builtins::help(ctx, command.as_deref(), config).await?;
-    Ok(())
+    let tags = truncate_str(quote.tags.as_deref().unwrap_or(""), 200);
+
+    if let Some(url) = &get_config().embed_thumbnail_url {
+        embed.thumbnail(url);
+    }
+    embed
+        .title(format!("Quote #{}", quote.id))
+        .url(quote.url())
+        .description(description)
+        .color(quote_color(quote))
+        .footer(|footer| footer.text(format!("Tags: {}", tags)))
 }
 
-async fn on_error(e: FrameworkError<'_, Data, Error>) {
-    use FrameworkError::*;
-    match e {
-        Setup { error, .. } => error!("Setup failed: {}", error),
-        EventHandler { error, .. } => error!("Error during event handler: {}", error),
-        Command { error, ctx } => {
-            let user_error_msg = error.to_string();
-            if let Err(e) = poise::say_reply(ctx, user_error_msg).await {
-                error!("Error while user command error: {}", e);
-            }
-        }
-        ArgumentParse { error, ctx, .. } => {
-            let mut usage = "Please check the help menu for usage information".into();
-            if let Some(help_text) = ctx.command().help_text {
-                usage = help_text();
+/// The color of the first tag with a configured color, or the default.
+fn quote_color(quote: &Quote) -> u32 {
+    let config = get_config();
+    split_tags(quote.tags.as_deref().unwrap_or(""))
+        .find_map(|tag| config.tag_colors.get(&tag.to_lowercase()))
+        .copied()
+        .unwrap_or(config.default_color)
+}
+
+/// Quote sends in progress, which shutdown waits on so that none are cut off.
+static SEND_BARRIER: Lazy<ShutdownBarrier> = Lazy::new(ShutdownBarrier::default);
+
+/// Send a quote to every quotes channel and the webhooks, returning the
+/// channels it couldn't be sent to.
+#[tracing::instrument(skip_all, fields(id = quote.id))]
+async fn send_quote(quote: &Quote, http: &dyn DiscordHttp) -> Vec<serenity::ChannelId> {
+    let _in_flight = SEND_BARRIER.enter();
+    // the watcher has already marked it seen, so it won't come up again
+    if let Some(tag) = quote.ignored_tag() {
+        debug!(id = quote.id, tag, "Not sending quote with ignored tag");
+        return Vec::new();
+    }
+    if get_args().dry_run {
+        println!("[dry run] Would send quote {}", quote);
+        return Vec::new();
+    }
+
+    let failed = send_quote_to(quote, http, &get_config().quotes_channel_ids).await;
+    // in the background, so a slow webhook can't hold up the next quote or
+    // look like a stuck send to the watchdog
+    let in_flight = SEND_BARRIER.enter();
+    let quote = quote.clone();
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        send_webhooks(&quote).await;
+    });
+    failed
+}
+
+/// Send a quote to each of `channels`, returning those it couldn't be sent to.
+async fn send_quote_to(
+    quote: &Quote,
+    http: &dyn DiscordHttp,
+    channels: &[serenity::ChannelId],
+) -> Vec<serenity::ChannelId> {
+    let _in_flight = SEND_BARRIER.enter();
+    // a failure in one channel shouldn't keep the quote from the others
+    let mut failed = Vec::new();
+    for &channel in channels {
+        info!(id = quote.id, %channel, "Submitting quote to discord");
+        match http.send_quote_embed(channel.0, quote).await {
+            Ok(()) => {
+                METRICS.discord_sends_succeeded.inc();
+                info!(%channel, "Sent quote to discord");
             }
-            let user_error_msg = format!("**{}**\n{}", error, usage);
-            if let Err(e) = poise::say_reply(ctx, user_error_msg).await {
-                error!("Error while user command error: {}", e);
+            Err(e) => {
+                METRICS.discord_sends_failed.inc();
+                error!(id = quote.id, %channel, "Couldn't send quote: {}", e);
+                failed.push(channel);
             }
         }
-        GuildOnly { ctx } => {
-            if let Err(e) = poise::say_reply(ctx, "Command is only allowed in servers!").await {
-                error!("Error while user command error: {}", e);
+    }
+    failed
+}
+
+/// Remember which message a quote was sent as, and the thread started on
+/// it if any, so they can be found again.
+fn record_sent_message(
+    quote_id: i64,
+    message: &serenity::Message,
+    thread: Option<serenity::ChannelId>,
+) -> ah::Result<()> {
+    get_db()?.execute(
+        "INSERT OR REPLACE INTO sent_messages (quote_id, message_id, channel_id, sent_at, thread_id)
+         VALUES (?1, ?2, ?3, datetime('now'), ?4)",
+        sql::params![
+            quote_id,
+            message.id.0,
+            message.channel_id.0,
+            thread.map(|thread| thread.0)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Keep a quote that couldn't be sent to `channels` so that it can be
+/// replayed there later, without resending it where it did get through.
+fn record_failed_quote(quote: &Quote, channels: &[serenity::ChannelId]) -> ah::Result<()> {
+    let conn = get_db()?;
+    for channel in channels {
+        conn.execute(
+            "INSERT INTO failed_quotes (id, text, tags, failed_at, channel_id)
+             VALUES (?1, ?2, ?3, datetime('now'), ?4)",
+            sql::params![quote.id, quote.text, quote.tags, channel.0],
+        )?;
+    }
+    Ok(())
+}
+
+/// Send a quote embed, retrying transient failures with exponential backoff.
+async fn send_with_retry(
+    channel: serenity::ChannelId,
+    quote: &Quote,
+    http: &serenity::Http,
+) -> serenity::Result<serenity::Message> {
+    const MAX_RETRIES: u32 = 5;
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        match channel
+            .send_message(http, |msg| {
+                // role pings only notify from the message content, not the embed
+                if let Some(role) = get_config().notify_role_id {
+                    msg.content(role.mention());
+                }
+                msg.embed(|embed| quote_embed(quote, get_config().message_prefix.as_deref(), embed))
+            })
+            .await
+        {
+            Err(e) if attempt < MAX_RETRIES && is_transient(&e) => {
+                attempt += 1;
+                // jitter so that retries for several quotes don't line up
+                let sleep = delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+                warn!(
+                    id = quote.id,
+                    %channel,
+                    attempt,
+                    "Couldn't send quote, retrying in {:?}: {}",
+                    sleep,
+                    e
+                );
+                tokio::time::sleep(sleep).await;
+                delay *= 2;
             }
+            result => return result,
         }
-        DmOnly { ctx } => {
-            if let Err(e) = poise::say_reply(ctx, "Command is only allowed in DM").await {
-                error!("Error while user command error: {}", e);
+    }
+}
+
+/// Whether a failed discord request might succeed if tried again.
+fn is_transient(e: &serenity::Error) -> bool {
+    match e {
+        serenity::Error::Http(e) => match &**e {
+            serenity::HttpError::UnsuccessfulRequest(response) => {
+                response.status_code.is_server_error() || response.status_code.as_u16() == 429
             }
-        }
-        UnknownCommand { .. } => error!("Somehow got an unknown command error?"),
-        _ => error!("UNHANDLED ERROR OCCURRED: {:?}", e),
+            serenity::HttpError::Request(_) => true,
+            _ => false,
+        },
+        _ => false,
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> ah::Result<()> {
-    tracing_subscriber::fmt::init();
+/// Whether a failed discord request was for something that doesn't exist,
+/// e.g. a member who's left.
+fn is_not_found(e: &serenity::Error) -> bool {
+    matches!(
+        e,
+        serenity::Error::Http(e) if matches!(
+            &**e,
+            serenity::HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 404
+        )
+    )
+}
+
+/// How long to wait on each webhook.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Mirror a quote to the configured webhooks. Failures are only logged.
+async fn send_webhooks(quote: &Quote) {
+    let payload = serde_json::json!({
+        "text": quote.text,
+        "tags": quote.tags.as_deref().unwrap_or(""),
+        "id": quote.id,
+    });
+    for url in &get_config().webhook_urls {
+        let response = get_client()
+            .post(url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(&payload)
+            .send()
+            .await;
+        match response.and_then(reqwest::Response::error_for_status) {
+            Ok(_) => info!(id = quote.id, url, "Sent quote to webhook"),
+            Err(e) => warn!(id = quote.id, url, "Couldn't send quote to webhook: {}", e),
+        }
+    }
+}
+
+/// Page through the most recent quotes.
+#[poise::command(slash_command, prefix_command)]
+async fn browse(ctx: Context<'_>) -> Result<(), Error> {
+    const BROWSE_COUNT: usize = 10;
+    let quotes = if let Some(quotes) = ctx.data().quote_cache.recent(BROWSE_COUNT) {
+        quotes
+    } else {
+        let conn = get_quotes_db()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, quote, tags FROM {} ORDER BY id DESC LIMIT ?1",
+            QuoteSchema::detect(&conn)?.table()
+        ))?;
+        let quotes = stmt
+            .query_map([BROWSE_COUNT], Quote::from_row)?
+            .collect::<Result<Vec<Quote>, _>>()?;
+        quotes
+    };
+    if quotes.is_empty() {
+        return Err(BotError::user("There are no quotes yet."));
+    }
+
+    paginate(ctx, quotes.len(), |page, embed| {
+        quote_embed(&quotes[page], None, embed)
+            .author(|a| a.name(format!("Quote {} of {}", page + 1, quotes.len())))
+    })
+    .await
+}
+
+/// Show an embed with Previous/Next buttons to flip through `page_count`
+/// pages, each drawn by `render`.
+async fn paginate(
+    ctx: Context<'_>,
+    page_count: usize,
+    render: impl Fn(usize, &mut serenity::CreateEmbed) -> &mut serenity::CreateEmbed + Send + Sync,
+) -> Result<(), Error> {
+    // button ids are scoped to this invocation so that concurrent paginators
+    // don't steal each other's clicks
+    let prev_id = format!("{}prev", ctx.id());
+    let next_id = format!("{}next", ctx.id());
+
+    let mut page = 0;
+    ctx.send(|reply| {
+        reply.embed(|embed| render(page, embed));
+        if page_count > 1 {
+            reply.components(|c| {
+                c.create_action_row(|row| {
+                    row.create_button(|b| b.custom_id(&prev_id).label("Previous"))
+                        .create_button(|b| b.custom_id(&next_id).label("Next"))
+                })
+            });
+        }
+        reply
+    })
+    .await?;
+    if page_count <= 1 {
+        return Ok(());
+    }
+
+    let ctx_id = ctx.id();
+    while let Some(press) = serenity::CollectComponentInteraction::new(ctx)
+        .filter(move |press| press.data.custom_id.starts_with(&ctx_id.to_string()))
+        .timeout(Duration::from_secs(600))
+        .await
+    {
+        if press.data.custom_id == next_id {
+            page = (page + 1) % page_count;
+        } else if press.data.custom_id == prev_id {
+            page = page.checked_sub(1).unwrap_or(page_count - 1);
+        } else {
+            continue;
+        }
+
+        press
+            .create_interaction_response(ctx, |resp| {
+                resp.kind(serenity::InteractionResponseType::UpdateMessage)
+                    .interaction_response_data(|data| data.embed(|embed| render(page, embed)))
+            })
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Split a quote's space separated tag string into individual tags.
+fn split_tags(tags: &str) -> impl Iterator<Item = &str> {
+    tags.split_whitespace()
+}
+
+/// List every tag in use.
+#[poise::command(slash_command, prefix_command)]
+async fn tag_list(ctx: Context<'_>) -> Result<(), Error> {
+    const TAGS_PER_PAGE: usize = 50;
+    let tags = {
+        let conn = get_quotes_db()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT tags FROM {} WHERE tags IS NOT NULL",
+            QuoteSchema::detect(&conn)?.table()
+        ))?;
+        let mut tags = std::collections::BTreeSet::new();
+        for tag_string in stmt.query_map([], |r| r.get::<_, String>(0))? {
+            tags.extend(split_tags(&tag_string?).map(str::to_owned));
+        }
+        tags.into_iter().collect::<Vec<_>>()
+    };
+    if tags.is_empty() {
+        return Err(BotError::user("No quotes have tags yet."));
+    }
+
+    let pages: Vec<&[String]> = tags.chunks(TAGS_PER_PAGE).collect();
+    paginate(ctx, pages.len(), |page, embed| {
+        embed
+            .title(format!("Tags ({} total)", tags.len()))
+            .description(truncate_str(&pages[page].join(", "), 4000))
+            .footer(|f| f.text(format!("Page {} of {}", page + 1, pages.len())))
+            .color(get_config().default_color)
+    })
+    .await
+}
+
+/// Show the most used tags.
+#[poise::command(slash_command, prefix_command)]
+async fn top_tags(
+    ctx: Context<'_>,
+    #[description = "how many tags to show, 10 by default"]
+    #[min = 1]
+    #[max = 25]
+    limit: Option<u8>,
+) -> Result<(), Error> {
+    let limit = limit.unwrap_or(10).clamp(1, 25) as usize;
+    let mut counts = {
+        let conn = get_quotes_db()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT tags FROM {} WHERE tags IS NOT NULL",
+            QuoteSchema::detect(&conn)?.table()
+        ))?;
+        let mut counts = std::collections::HashMap::<String, usize>::new();
+        for tag_string in stmt.query_map([], |r| r.get::<_, String>(0))? {
+            for tag in split_tags(&tag_string?) {
+                *counts.entry(tag.to_owned()).or_default() += 1;
+            }
+        }
+        counts.into_iter().collect::<Vec<_>>()
+    };
+    if counts.is_empty() {
+        return Err(BotError::user("No quotes have tags yet."));
+    }
+    counts.sort_by(|(a_tag, a_count), (b_tag, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_tag.cmp(b_tag))
+    });
+
+    let lines: Vec<String> = counts
+        .iter()
+        .take(limit)
+        .enumerate()
+        .map(|(i, (tag, count))| {
+            let tag = truncate_str(tag, 100);
+            match count {
+                1 => format!("{}. `{}`: 1 quote", i + 1, tag),
+                _ => format!("{}. `{}`: {} quotes", i + 1, tag, count),
+            }
+        })
+        .collect();
+    ctx.send(|reply| {
+        reply.embed(|embed| {
+            embed
+                .title(format!("Top {} tags", lines.len()))
+                .description(lines.join("\n"))
+                .color(get_config().default_color)
+        })
+    })
+    .await?;
+    Ok(())
+}
+
+/// Show a specific quote by its id.
+#[poise::command(slash_command, prefix_command)]
+async fn quote_id(
+    ctx: Context<'_>,
+    #[description = "quote id, as shown on titanic"] id: i64,
+) -> Result<(), Error> {
+    let quote = get_quote(id)?
+        .ok_or_else(|| BotError::user(format!("There's no quote with id {}.", id)))?;
+    let sent = !watcher::is_new(&*get_quotes_db()?, id)?;
+
+    ctx.send(|reply| {
+        reply
+            .content(if sent {
+                "Already sent to the quotes channels."
+            } else {
+                "Not sent to the quotes channels yet."
+            })
+            .embed(|embed| quote_embed(&quote, None, embed))
+            .ephemeral(true)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Show a quote's text as typed, without rendering its markdown.
+#[poise::command(slash_command, prefix_command)]
+async fn quote_raw(
+    ctx: Context<'_>,
+    #[description = "quote id, as shown on titanic"] id: i64,
+) -> Result<(), Error> {
+    let quote = get_quote(id)?
+        .ok_or_else(|| BotError::user(format!("There's no quote with id {}.", id)))?;
+
+    // escaped so the quote can't close the code block early
+    let text = quote.text.replace('`', "\\`");
+    let content = format!("```\n{}\n```", truncate_str(&text, 1990));
+    poise::send_reply(ctx, |r| r.content(content).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Show a random quote.
+#[poise::command(slash_command, prefix_command, aliases("r"))]
+async fn random(
+    ctx: Context<'_>,
+    #[description = "pick deterministically, for reproducible results"] seed: Option<i64>,
+) -> Result<(), Error> {
+    let quote = if let Some(quotes) = ctx.data().quote_cache.all() {
+        match seed {
+            None => quotes.choose(&mut rand::thread_rng()).cloned(),
+            // the same pick as the query below, since the cache is in id order
+            Some(seed) => quotes
+                .get(seed.rem_euclid(quotes.len().max(1) as i64) as usize)
+                .cloned(),
+        }
+    } else {
+        let conn = get_quotes_db()?;
+        let table = QuoteSchema::detect(&conn)?.table();
+        match seed {
+            None => conn
+                .query_row(
+                    &format!(
+                        "SELECT id, quote, tags FROM {} ORDER BY RANDOM() LIMIT 1",
+                        table
+                    ),
+                    [],
+                    Quote::from_row,
+                )
+                .optional()?,
+            Some(seed) => {
+                // sqlite's RANDOM() can't be seeded, so index into the quotes instead
+                let count: i64 =
+                    conn.query_row("SELECT COUNT(*) FROM quotes.quotes", [], |r| r.get(0))?;
+                if count == 0 {
+                    None
+                } else {
+                    conn.query_row(
+                        &format!(
+                            "SELECT id, quote, tags FROM {} ORDER BY id LIMIT 1 OFFSET ?1",
+                            table
+                        ),
+                        [seed.rem_euclid(count)],
+                        Quote::from_row,
+                    )
+                    .optional()?
+                }
+            }
+        }
+    }
+    .ok_or_else(|| BotError::user("There are no quotes yet."))?;
+
+    ctx.send(|reply| {
+        reply
+            .embed(|embed| quote_embed(&quote, None, embed))
+            .ephemeral(true)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Delete a quote from titanic, and its messages here if we sent any.
+#[poise::command(slash_command, prefix_command)]
+async fn delete_quote(
+    ctx: Context<'_>,
+    #[description = "quote id, as shown on titanic"] id: i64,
+) -> Result<(), Error> {
+    let (user, pass) = get_credentials(*ctx.author().id.as_u64(), &get_config().default_server)?;
+    let request = get_client()
+        .delete(get_titanic_url())
+        .query(&[("q", id)])
+        .basic_auth(user, Some(pass));
+    let Some(response) = send_titanic_write(request).await? else {
+        return reply_dry_run(ctx).await;
+    };
+    check_titanic_response(response).await?;
+
+    ctx.data().quote_cache.forget(id);
+    // the quote is gone from titanic either way, so that's what to report
+    let reply = match delete_quote_message(ctx.serenity_context(), id).await {
+        Ok(true) => format!("Deleted quote #{} and its messages.", id),
+        Ok(false) => format!("Deleted quote #{}.", id),
+        Err(e) => {
+            error!(
+                id,
+                "Couldn't delete the messages for a deleted quote: {}", e
+            );
+            format!("Deleted quote #{}, but couldn't delete its messages.", id)
+        }
+    };
+    poise::send_reply(ctx, |r| r.content(reply).ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Fix the text of a quote you submitted.
+#[poise::command(slash_command, dm_only)]
+async fn quote_edit(
+    ctx: Context<'_>,
+    #[description = "quote id, as shown on titanic"] id: i64,
+    #[description = "new quote text"] text: String,
+) -> Result<(), Error> {
+    let (user, pass) = get_credentials(*ctx.author().id.as_u64(), &get_config().default_server)?;
+
+    // titanic has the final say, but catch edits to other people's quotes
+    // early when the quotes db records who wrote them
+    let author: Option<String> = {
+        let conn = get_quotes_db()?;
+        if let Some(table) = QuoteSchema::detect(&conn)?.table_with_author() {
+            conn.query_row(
+                &format!("SELECT author FROM {} WHERE id = ?1", table),
+                [id],
+                |r| r.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| BotError::user(format!("There's no quote with id {}.", id)))?
+        } else {
+            None
+        }
+    };
+    if author.is_some_and(|author| author != user) {
+        return Err(BotError::user("You can only edit quotes you submitted."));
+    }
+
+    let request = get_client()
+        .patch(get_titanic_url())
+        .query(&[("q", id)])
+        .basic_auth(user, Some(pass))
+        .form(&[("quote", text.trim())]);
+    let Some(response) = send_titanic_write(request).await? else {
+        return reply_dry_run(ctx).await;
+    };
+    check_titanic_response(response).await?;
+    ctx.data().quote_cache.forget(id);
+
+    poise::say_reply(ctx, format!("Updated quote #{}.", id)).await?;
+    Ok(())
+}
+
+/// Replace the tags on a quote.
+#[poise::command(slash_command, prefix_command)]
+async fn quote_tags(
+    ctx: Context<'_>,
+    #[description = "quote id, as shown on titanic"] id: i64,
+    #[description = "space-separated \"tag:[tag]\"s, and \"server:[name]\" if not the default"]
+    #[rest]
+    tags: String,
+) -> Result<(), Error> {
+    let (tag_string, server, rest) = parse_prefixes(&tags)?;
+    if !rest.is_empty() {
+        return Err(BotError::user(format!(
+            "Tags must look like \"tag:[tag]\", not {:?}.",
+            truncate_str(rest, 50)
+        )));
+    }
+    let server = get_server(server)?;
+    let (user, pass) = get_credentials(*ctx.author().id.as_u64(), &server.name)?;
+
+    let request = get_client()
+        .patch(&server.url)
+        .query(&[("q", id)])
+        .basic_auth(user, Some(pass))
+        .form(&[("tags", &tag_string)]);
+    let Some(response) = send_titanic_write(request).await? else {
+        return reply_dry_run(ctx).await;
+    };
+    check_titanic_response(response).await?;
+    ctx.data().quote_cache.forget(id);
+
+    poise::send_reply(ctx, |r| {
+        r.content(format!("Updated the tags on quote #{}.", id))
+            .ephemeral(true)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Delete the bot's messages for a quote, and forget them along with the
+/// quote's ratings. Returns whether any message was deleted.
+async fn delete_quote_message(ctx: &serenity::Context, id: i64) -> ah::Result<bool> {
+    let sent: Vec<(u64, u64)> = {
+        let conn = get_db()?;
+        let mut stmt =
+            conn.prepare("SELECT channel_id, message_id FROM sent_messages WHERE quote_id = ?1")?;
+        let sent = stmt
+            .query_map([id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        sent
+    };
+    let mut deleted = false;
+    for (channel_id, message_id) in sent {
+        match ctx.http.delete_message(channel_id, message_id).await {
+            Ok(()) => deleted = true,
+            // already deleted by hand
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let conn = get_db()?;
+    conn.execute("DELETE FROM sent_messages WHERE quote_id = ?1", [id])?;
+    conn.execute("DELETE FROM quote_ratings WHERE quote_id = ?1", [id])?;
+    Ok(deleted)
+}
+
+/// Pin the message for a quote in the quotes channels.
+#[poise::command(slash_command, guild_only, required_permissions = "MANAGE_MESSAGES")]
+async fn pin_quote(
+    ctx: Context<'_>,
+    #[description = "quote id, as shown on titanic"] id: i64,
+) -> Result<(), Error> {
+    // Discord refuses to pin more than this per channel
+    const MAX_PINS: usize = 50;
+    let sent: Vec<(u64, u64)> = {
+        let conn = get_db()?;
+        let mut stmt =
+            conn.prepare("SELECT channel_id, message_id FROM sent_messages WHERE quote_id = ?1")?;
+        let sent = stmt
+            .query_map([id], |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        sent
+    };
+    if sent.is_empty() {
+        return Err(BotError::user(format!(
+            "I don't have a message for quote #{}.",
+            id
+        )));
+    }
+    if get_args().dry_run {
+        println!("[dry run] Would pin quote #{}", id);
+        return reply_dry_run(ctx).await;
+    }
+
+    let http = &ctx.serenity_context().http;
+    for (channel_id, message_id) in sent {
+        let channel = serenity::ChannelId(channel_id);
+        let pins = channel.pins(http).await?;
+        // pins come newest first
+        if pins.len() >= MAX_PINS {
+            if let Some(oldest) = pins.last() {
+                warn!(
+                    channel_id,
+                    message_id = oldest.id.0,
+                    "Pin limit reached, unpinning the oldest pin"
+                );
+                oldest.unpin(http).await?;
+            }
+        }
+        channel.pin(http, message_id).await?;
+    }
+    poise::say_reply(ctx, format!("Pinned quote #{}.", id)).await?;
+    Ok(())
+}
+
+/// Escape `%` and `_` in `s` so LIKE matches them literally, with `\` as the
+/// escape character.
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Search quotes by text or tag.
+#[poise::command(slash_command, prefix_command)]
+async fn search(
+    ctx: Context<'_>,
+    #[description = "text to look for in quote bodies and tags"]
+    #[rest]
+    query: String,
+) -> Result<(), Error> {
+    const MAX_RESULTS: usize = 5;
+    let pattern = format!("%{}%", escape_like(query.trim()));
+    let (matches, total) = {
+        let conn = get_quotes_db()?;
+        let table = QuoteSchema::detect(&conn)?.table();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, quote, tags FROM {} WHERE quote LIKE ?1 ESCAPE '\\' OR tags LIKE ?1 ESCAPE '\\'
+             ORDER BY id DESC LIMIT ?2",
+            table
+        ))?;
+        let matches = stmt
+            .query_map(sql::params![pattern, MAX_RESULTS], Quote::from_row)?
+            .collect::<Result<Vec<Quote>, _>>()?;
+        let total: usize = conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {} WHERE quote LIKE ?1 ESCAPE '\\' OR tags LIKE ?1 ESCAPE '\\'",
+                table
+            ),
+            [&pattern],
+            |r| r.get(0),
+        )?;
+        (matches, total)
+    };
+    if matches.is_empty() {
+        return Err(BotError::user(format!("No quotes matched \"{}\".", query)));
+    }
+
+    ctx.send(|reply| {
+        reply.embed(|embed| {
+            embed.title(format!(
+                "Search results for \"{}\"",
+                truncate_str(&query, 200)
+            ));
+            for quote in &matches {
+                embed.field(
+                    format!("#{}", quote.id),
+                    truncate_str(&quote.text, 200),
+                    false,
+                );
+            }
+            if total > matches.len() {
+                embed.footer(|f| f.text(format!("{} more results", total - matches.len())));
+            }
+            embed.color(get_config().default_color)
+        })
+    })
+    .await?;
+    Ok(())
+}
+
+/// Show quote and registration statistics.
+#[poise::command(slash_command, prefix_command)]
+async fn stats(ctx: Context<'_>) -> Result<(), Error> {
+    let (seen_count, quote_count, latest_id, user_count, submission_count, (upvotes, downvotes)) = {
+        let conn = get_quotes_db()?;
+        let (seen_count, quote_count) = watcher::quote_count(&conn)?;
+        let latest_id = watcher::latest_id(&conn)?;
+        let user_count: i64 = conn.query_row(
+            "SELECT COUNT(DISTINCT auth_user) FROM credentials",
+            [],
+            |r| r.get(0),
+        )?;
+        let submission_count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM submissions WHERE discord_id = ?1 AND success",
+            [ctx.author().id.as_u64()],
+            |r| r.get(0),
+        )?;
+        let ratings: (i64, i64) = conn.query_row(
+            "SELECT COALESCE(SUM(upvotes), 0), COALESCE(SUM(downvotes), 0) FROM quote_ratings",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        (
+            seen_count,
+            quote_count,
+            latest_id,
+            user_count,
+            submission_count,
+            ratings,
+        )
+    };
+
+    ctx.send(|reply| {
+        reply.embed(|embed| {
+            embed
+                .title("Hellquotes stats")
+                .field("Quotes", quote_count, true)
+                .field("Seen by the bot", seen_count, true)
+                .field("Registered users", user_count, true)
+                .field("Quotes you've sent", submission_count, true)
+                .field(
+                    "Latest quote",
+                    latest_id.map_or("none".to_owned(), |id| format!("#{}", id)),
+                    true,
+                )
+                .field(
+                    "Ratings",
+                    format!("👍 {} / 👎 {}", upvotes, downvotes),
+                    true,
+                )
+                .color(get_config().default_color)
+        })
+    })
+    .await?;
+    Ok(())
+}
+
+/// Check the bot's latency to Discord.
+#[poise::command(slash_command, prefix_command)]
+async fn ping(ctx: Context<'_>) -> Result<(), Error> {
+    // zero until the first heartbeat is acknowledged
+    let heartbeat = ctx.ping().await;
+    let start = Instant::now();
+    let reply = poise::send_reply(ctx, |r| r.content("Pinging...").ephemeral(true)).await?;
+    let round_trip = start.elapsed();
+    reply
+        .edit(ctx, |r| {
+            r.content("").embed(|embed| {
+                embed
+                    .title("Pong!")
+                    .description(format!(
+                        "Heartbeat: {}ms | Message: {}ms",
+                        heartbeat.as_millis(),
+                        round_trip.as_millis()
+                    ))
+                    .color(get_config().default_color)
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+/// Send feedback or a problem report to the bot's operator.
+#[poise::command(slash_command, prefix_command)]
+async fn feedback(
+    ctx: Context<'_>,
+    #[description = "what you'd like the operator to know"]
+    #[rest]
+    message: String,
+) -> Result<(), Error> {
+    let config = get_config();
+    let http = &ctx.serenity_context().http;
+    let author = ctx.author();
+    let content = format!(
+        "Feedback from {} ({}):\n{}",
+        author.tag(),
+        author.id,
+        message
+    );
+    let content = truncate_str(&content, 2000);
+    if get_args().dry_run {
+        println!("[dry run] Would send feedback: {}", content);
+        return reply_dry_run(ctx).await;
+    }
+    if let Some(channel) = config.feedback_channel_id {
+        channel.say(http, content).await?;
+    } else if let Some(admin) = config.admin_discord_id {
+        admin
+            .create_dm_channel(http)
+            .await?
+            .say(http, content)
+            .await?;
+    } else {
+        return Err(BotError::user("Feedback isn't set up for this bot."));
+    }
+    info!(user = %author.id, "Sent feedback");
+    poise::send_reply(ctx, |r| {
+        r.content("Thanks, your feedback was sent.").ephemeral(true)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Post an announcement to the quotes channels. Admins only, unless
+/// `[permissions]` lists who can.
+#[poise::command(slash_command, hide_in_help)]
+async fn announce(
+    ctx: Context<'_>,
+    #[description = "announcement title"] title: String,
+    #[description = "announcement text"] body: String,
+) -> Result<(), Error> {
+    let config = get_config();
+    if get_args().dry_run {
+        println!("[dry run] Would announce {:?}: {:?}", title, body);
+        return reply_dry_run(ctx).await;
+    }
+    let http = &ctx.serenity_context().http;
+    for channel in &config.quotes_channel_ids {
+        channel
+            .send_message(http, |msg| {
+                msg.embed(|embed| {
+                    embed
+                        .title(truncate_str(&title, 256))
+                        .description(truncate_str(&body, 4096))
+                        .color(config.default_color)
+                })
+            })
+            .await?;
+    }
+    info!(user = %ctx.author().id, "Posted announcement");
+    poise::send_reply(ctx, |r| r.content("Announcement posted.").ephemeral(true)).await?;
+    Ok(())
+}
+
+/// Forget which quotes have been sent, so every quote is sent again. Admin only.
+#[poise::command(slash_command, prefix_command, check = "is_admin", hide_in_help)]
+async fn reset_seen(ctx: Context<'_>) -> Result<(), Error> {
+    // wait for room rather than dropping it like a poll, since there's no
+    // other reset coming
+    ctx.data().poll_tx.send(WatcherRequest::Reset).await?;
+    warn!(user = %ctx.author().id, "Seen quotes reset");
+    poise::send_reply(ctx, |r| {
+        r.content("Seen quotes reset, every quote will be sent again on the next check.")
+            .ephemeral(true)
+    })
+    .await?;
+    Ok(())
+}
+
+/// Retry sending quotes that previously failed to send. Admin only.
+#[poise::command(slash_command, prefix_command, check = "is_admin", hide_in_help)]
+async fn replay_failed(ctx: Context<'_>) -> Result<(), Error> {
+    let failed = {
+        let conn = get_db()?;
+        let mut stmt = conn
+            .prepare("SELECT id, text, tags, channel_id, rowid FROM failed_quotes ORDER BY id")?;
+        let failed = stmt
+            .query_map([], |r| {
+                Ok((
+                    Quote::from_row(r)?,
+                    r.get::<_, Option<u64>>(3)?,
+                    r.get::<_, i64>(4)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        failed
+    };
+    if get_args().dry_run {
+        println!("[dry run] Would replay {} failed sends", failed.len());
+        return reply_dry_run(ctx).await;
+    }
+
+    let mut replayed = 0;
+    for (quote, channel, rowid) in &failed {
+        // rows from before failures were kept per channel cover every channel
+        let channels = match channel {
+            Some(channel) => vec![serenity::ChannelId(*channel)],
+            None => get_config().quotes_channel_ids.clone(),
+        };
+        let still_failed = send_quote_to(quote, &*ctx.serenity_context().http, &channels).await;
+        if still_failed.is_empty() {
+            get_db()?.execute("DELETE FROM failed_quotes WHERE rowid = ?1", [rowid])?;
+            replayed += 1;
+        } else {
+            warn!(
+                id = quote.id,
+                "Replay failed in {} channels",
+                still_failed.len()
+            );
+        }
+    }
+
+    poise::send_reply(ctx, |r| {
+        r.content(format!(
+            "Replayed {} of {} failed sends.",
+            replayed,
+            failed.len()
+        ))
+        .ephemeral(true)
+    })
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, poise::ChoiceParameter)]
+enum LeaderboardKind {
+    #[name = "tags"]
+    Tags,
+    #[name = "authors"]
+    Authors,
+}
+
+/// Show the most common tags or quote authors.
+#[poise::command(slash_command, prefix_command)]
+async fn leaderboard(
+    ctx: Context<'_>,
+    #[description = "what to rank, defaults to tags"]
+    #[rename = "type"]
+    kind: Option<LeaderboardKind>,
+    #[description = "number of entries to show, 1-25"]
+    #[min = 1]
+    #[max = 25]
+    limit: Option<u8>,
+) -> Result<(), Error> {
+    let kind = kind.unwrap_or(LeaderboardKind::Tags);
+    let limit = limit.unwrap_or(10).clamp(1, 25);
+    let column = match kind {
+        LeaderboardKind::Tags => "tags",
+        LeaderboardKind::Authors => "author",
+    };
+
+    let rows = {
+        let conn = get_quotes_db()?;
+        let schema = QuoteSchema::detect(&conn)?;
+        let table = match kind {
+            LeaderboardKind::Tags => schema.has_tags().then(|| schema.table()),
+            LeaderboardKind::Authors => schema.table_with_author(),
+        };
+        let Some(table) = table else {
+            return Err(BotError::user(format!(
+                "This quotes database doesn't record {}.",
+                column
+            )));
+        };
+        // column is one of the fixed names above, so this can't be injected
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {0}, COUNT(*) AS c FROM {1} WHERE {0} IS NOT NULL AND {0} != ''
+             GROUP BY {0} ORDER BY c DESC LIMIT ?1",
+            column, table
+        ))?;
+        let rows = stmt
+            .query_map([limit], |r| {
+                Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows
+    };
+    if rows.is_empty() {
+        return Err(BotError::user("There's nothing to rank yet."));
+    }
+
+    let table = rows
+        .iter()
+        .enumerate()
+        .map(|(i, (name, count))| format!("{}. {} ({})", i + 1, truncate_str(name, 100), count))
+        .collect::<Vec<_>>()
+        .join("\n");
+    ctx.send(|reply| {
+        reply.embed(|embed| {
+            embed
+                .title(format!("Top {}", column))
+                .description(table)
+                .color(get_config().default_color)
+        })
+    })
+    .await?;
+    Ok(())
+}
+
+/// Allow only users listed in the `admin_ids` config.
+async fn is_admin(ctx: Context<'_>) -> Result<bool, Error> {
+    Ok(get_config().admin_ids.contains(&ctx.author().id))
+}
+
+/// Check for new quotes now, in case the watcher missed one. Admin only.
+#[poise::command(slash_command, prefix_command, check = "is_admin", hide_in_help)]
+async fn force_check(ctx: Context<'_>) -> Result<(), Error> {
+    let reply = match ctx.data().poll_tx.try_send(WatcherRequest::Poll) {
+        Ok(()) => "Quote check requested.",
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            "Quote checks are already queued, nothing more to do."
+        }
+        Err(e @ mpsc::error::TrySendError::Closed(_)) => return Err(e.into()),
+    };
+    poise::send_reply(ctx, |r| r.content(reply).ephemeral(true)).await?;
+    Ok(())
+}
+
+#[poise::command(prefix_command, slash_command, aliases("h"))]
+async fn help(
+    ctx: Context<'_>,
+    #[description = "Specific command to show help about"] command: Option<String>,
+) -> Result<(), Error> {
+    let config = poise::builtins::HelpConfiguration {
+        extra_text_at_bottom: "Type /help command for more info on a command.",
+        ..Default::default()
+    };
+    poise::builtins::help(ctx, command.as_deref(), config).await?;
+    Ok(())
+}
+
+async fn event_handler(
+    ctx: &serenity::Context,
+    event: &poise::Event<'_>,
+    _framework: poise::FrameworkContext<'_, Data, Error>,
+    _data: &Data,
+) -> Result<(), Error> {
+    match event {
+        poise::Event::ReactionAdd { add_reaction } => handle_rating(ctx, add_reaction, true).await,
+        poise::Event::ReactionRemove { removed_reaction } => {
+            handle_rating(ctx, removed_reaction, false).await
+        }
+        poise::Event::GuildMemberRemoval { guild_id, user, .. } => {
+            handle_member_removal(ctx, *guild_id, user).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// With `auto_delete_credentials`, forget users who've left every server the
+/// bot is in.
+async fn handle_member_removal(
+    ctx: &serenity::Context,
+    left: serenity::GuildId,
+    user: &serenity::User,
+) -> Result<(), Error> {
+    let config = get_config();
+    if !config.auto_delete_credentials {
+        return Ok(());
+    }
+    for guild in ctx.cache.guilds() {
+        if guild == left {
+            continue;
+        }
+        if ctx.cache.member(guild, user.id).is_some() {
+            return Ok(());
+        }
+        match ctx.http.get_member(guild.0, user.id.0).await {
+            Ok(_) => return Ok(()),
+            Err(e) if is_not_found(&e) => {}
+            // keep the credentials rather than lose them to an outage
+            Err(e) => {
+                warn!(user = %user.id, %guild, "Couldn't check whether user is still a member: {}", e);
+                return Ok(());
+            }
+        }
+    }
+    let deleted = get_db()?.execute(
+        "DELETE FROM credentials WHERE discord_id = ?1",
+        [user.id.as_u64()],
+    )?;
+    if deleted == 0 {
+        return Ok(());
+    }
+    info!(user = %user.id, "Deleted credentials of a user who left every server");
+    if config.notify_deleted_credentials {
+        let dm = user
+            .direct_message(ctx, |m| {
+                m.content(
+                    "You've left every server I'm in, so I've deleted your titanic login. \
+                     Use /register if you come back.",
+                )
+            })
+            .await;
+        if let Err(e) = dm {
+            warn!(user = %user.id, "Couldn't tell user their credentials were deleted: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Turn 👍/👎 reactions on quote messages into ratings on titanic.
+async fn handle_rating(
+    ctx: &serenity::Context,
+    reaction: &serenity::Reaction,
+    added: bool,
+) -> Result<(), Error> {
+    if !get_config()
+        .quotes_channel_ids
+        .contains(&reaction.channel_id)
+    {
+        return Ok(());
+    }
+    let (rating, column) = match &reaction.emoji {
+        serenity::ReactionType::Unicode(emoji) if emoji == "👍" => (1, "upvotes"),
+        serenity::ReactionType::Unicode(emoji) if emoji == "👎" => (-1, "downvotes"),
+        _ => return Ok(()),
+    };
+    let user_id = match reaction.user_id {
+        Some(user_id) if user_id != ctx.cache.current_user_id() => user_id,
+        _ => return Ok(()),
+    };
+    let quote_id: Option<i64> = get_db()?
+        .query_row(
+            "SELECT quote_id FROM sent_messages WHERE message_id = ?1",
+            [reaction.message_id.0],
+            |r| r.get(0),
+        )
+        .optional()?;
+    let quote_id = match quote_id {
+        Some(quote_id) => quote_id,
+        None => return Ok(()),
+    };
+
+    // column is one of the fixed names above, so this can't be injected
+    get_db()?.execute(
+        &format!(
+            "INSERT INTO quote_ratings (quote_id, {0}) VALUES (?1, MAX(?2, 0))
+             ON CONFLICT(quote_id) DO UPDATE SET {0} = MAX({0} + ?2, 0)",
+            column
+        ),
+        sql::params![quote_id, if added { 1 } else { -1 }],
+    )?;
+
+    let (user, pass) = match get_credentials(user_id.0, &get_config().default_server) {
+        Ok(credentials) => credentials,
+        Err(BotError::UserError(_)) => {
+            if added {
+                user_id
+                    .create_dm_channel(ctx)
+                    .await?
+                    .say(
+                        ctx,
+                        "Your rating was counted here, but to send it to titanic, \
+                         register by DMing me the /register command.",
+                    )
+                    .await?;
+            }
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let request = get_client()
+        .post(get_titanic_url())
+        .basic_auth(user, Some(pass))
+        .form(&[
+            ("rate", quote_id),
+            ("rating", if added { rating } else { 0 }),
+        ]);
+    if let Some(response) = send_titanic_write(request).await? {
+        if !response.status().is_success() {
+            warn!(
+                quote_id,
+                status = %response.status(),
+                "Titanic rejected rating"
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn on_error(e: FrameworkError<'_, Data, Error>) {
+    use FrameworkError::*;
+    match e {
+        Setup { error, .. } => error!("Setup failed: {}", error),
+        EventHandler { error, .. } => error!("Error during event handler: {}", error),
+        Command { error, ctx } => {
+            let user_error_msg = match error {
+                BotError::UserError(msg) => msg,
+                BotError::InternalError(e) => {
+                    error!(command = %ctx.command().name, "Internal error: {:?}", e);
+                    "Something went wrong on our end. Please try again later.".to_owned()
+                }
+            };
+            if let Err(e) = poise::say_reply(ctx, user_error_msg).await {
+                error!("Error while user command error: {}", e);
+            }
+        }
+        ArgumentParse { error, ctx, .. } => {
+            let mut usage = "Please check the help menu for usage information".into();
+            if let Some(help_text) = ctx.command().help_text {
+                usage = help_text();
+            }
+            let user_error_msg = format!("**{}**\n{}", error, usage);
+            if let Err(e) = poise::say_reply(ctx, user_error_msg).await {
+                error!("Error while user command error: {}", e);
+            }
+        }
+        GuildOnly { ctx } => {
+            if let Err(e) = poise::say_reply(ctx, "Command is only allowed in servers!").await {
+                error!("Error while user command error: {}", e);
+            }
+        }
+        DmOnly { ctx } => {
+            if let Err(e) = poise::say_reply(ctx, "Command is only allowed in DM").await {
+                error!("Error while user command error: {}", e);
+            }
+        }
+        CommandCheckFailed { error, ctx } => {
+            let user_error_msg = match error {
+                Some(BotError::UserError(msg)) => msg,
+                Some(BotError::InternalError(e)) => {
+                    error!(command = %ctx.command().name, "Internal error in check: {:?}", e);
+                    "Something went wrong on our end. Please try again later.".to_owned()
+                }
+                None => "You don't have permission to use this command.".to_owned(),
+            };
+            if let Err(e) =
+                poise::send_reply(ctx, |r| r.content(user_error_msg).ephemeral(true)).await
+            {
+                error!("Error while user command error: {}", e);
+            }
+        }
+        UnknownCommand { .. } => error!("Somehow got an unknown command error?"),
+        _ => error!("UNHANDLED ERROR OCCURRED: {:?}", e),
+    }
+}
+
+/// Wait for CTRL+C, or on unix SIGTERM, which is how systemd and docker
+/// ask us to stop.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+fn commands() -> Vec<poise::Command<Data, Error>> {
+    vec![
+        register(),
+        unregister(),
+        whoami(),
+        quote(),
+        browse(),
+        search(),
+        quote_id(),
+        quote_raw(),
+        delete_quote(),
+        quote_edit(),
+        quote_tags(),
+        pin_quote(),
+        random(),
+        stats(),
+        leaderboard(),
+        tag_list(),
+        top_tags(),
+        force_check(),
+        reset_seen(),
+        announce(),
+        feedback(),
+        ping(),
+        replay_failed(),
+        help(),
+    ]
+}
+
+/// Start the quote poller, sending new quotes with `http`, and everything
+/// that keeps it going: the fs watcher, the watchdog and the SIGUSR1 handler.
+fn start_watching(
+    http: Arc<dyn DiscordHttp>,
+    shutdown: &Shutdown,
+) -> ah::Result<(mpsc::Sender<WatcherRequest>, Arc<QuoteCache>)> {
+    let config = get_config();
+    let quote_cache = Arc::new(QuoteCache::load(config.cache_capacity)?);
+    let (poll_tx, poller_health, poller_task) = watcher::create_poller(
+        http,
+        quote_cache.clone(),
+        &config.db_path,
+        &config.quotes_db_path,
+        config.poll_interval,
+        shutdown.clone(),
+    )?;
+    watcher::spawn_watchdog(poller_health, config.stall_timeout, shutdown.clone());
+    let poller_task = shutdown.wrap_vital(poller_task);
+    let poller_task = shutdown.wrap_cancel(poller_task);
+    tokio::spawn(poller_task);
+    #[cfg(unix)]
+    {
+        let reopen_tx = poll_tx.clone();
+        let reopen_task = async move {
+            if let Err(e) = watcher::reopen_on_sigusr1(reopen_tx).await {
+                error!("Couldn't handle SIGUSR1: {}", e);
+            }
+        };
+        tokio::spawn(shutdown.wrap_cancel(reopen_task));
+    }
+
+    let fs_watcher =
+        watcher::configure_fs_watcher(poll_tx.clone(), &config.quotes_db_path, config.debounce)?;
+    tokio::spawn(shutdown.wrap_cancel(fs_watcher));
+    Ok((poll_tx, quote_cache))
+}
+
+/// Fail unless every quotes channel exists and the bot can see it, so a bad
+/// `quotes_channel_id` shows up at startup rather than when a quote is sent.
+async fn check_quotes_channels(http: &serenity::Http) -> ah::Result<()> {
+    for channel in &get_config().quotes_channel_ids {
+        match http.get_channel(channel.0).await {
+            Ok(serenity::Channel::Guild(found)) => {
+                info!(%channel, name = %found.name, "Found quotes channel")
+            }
+            Ok(_) => ah::bail!("quotes_channel_id {} isn't a server channel", channel),
+            Err(e) => ah::bail!(
+                "quotes_channel_id {} isn't a channel the bot can access: {}",
+                channel,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// For `--list-commands`: a markdown table of the commands and their parameters.
+fn list_commands(commands: &[poise::Command<Data, Error>]) -> String {
+    let cell = |text: &str| text.replace('|', "\\|").replace('\n', " ");
+    let mut table = String::from(
+        "| Command | Description | Parameters | DM only | Guild only |\n\
+         |---------|-------------|------------|---------|------------|\n",
+    );
+    for command in commands {
+        let parameters = command
+            .parameters
+            .iter()
+            .map(|p| match &p.description {
+                Some(description) => format!("`{}`: {}", p.name, cell(description)),
+                None => format!("`{}`", p.name),
+            })
+            .collect::<Vec<_>>()
+            .join("<br>");
+        table += &format!(
+            "| `/{}` | {} | {} | {} | {} |\n",
+            command.name,
+            cell(command.description.as_deref().unwrap_or("")),
+            parameters,
+            if command.dm_only { "yes" } else { "no" },
+            if command.guild_only { "yes" } else { "no" },
+        );
+    }
+    table
+}
+
+/// For `--once`: register commands, send any new quotes, and return, without
+/// connecting to the gateway.
+async fn run_once() -> ah::Result<()> {
+    let config = get_config();
+    let http = serenity::Http::new(&config.token);
+    if get_args().dry_run {
+        info!("Dry run, not registering commands.");
+    } else {
+        let app = http.get_current_application_info().await?;
+        http.set_application_id(app.id.0);
+        poise::builtins::register_globally(&http, &commands()).await?;
+    }
+
+    let quotes = watcher::poll_once(&config.db_path, &config.quotes_db_path)?;
+    for quote in quotes {
+        let failed = send_quote(&quote, &http).await;
+        if !failed.is_empty() {
+            error!(
+                id = quote.id,
+                "Giving up on sending quote to {} channels",
+                failed.len()
+            );
+            record_failed_quote(&quote, &failed)?;
+        }
+    }
+    wait_for_webhooks().await;
+    Ok(())
+}
+
+/// For `--replay-since`: send every quote after `id`, waiting `replay_delay_ms`
+/// between them to stay clear of Discord's rate limits.
+async fn replay_since(id: i64) -> ah::Result<()> {
+    let config = get_config();
+    // marked seen, so a running bot doesn't send them again as well
+    let quotes: Vec<Quote> = watcher::QuoteWatcher::new(&config.db_path, &config.quotes_db_path)?
+        .since(id)?
+        .collect();
+    info!("Replaying {} quotes after #{}", quotes.len(), id);
+
+    let http = serenity::Http::new(&get_config().token);
+    for (i, quote) in quotes.iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(get_config().replay_delay).await;
+        }
+        let failed = send_quote(quote, &http).await;
+        if !failed.is_empty() {
+            error!(
+                id = quote.id,
+                "Giving up on sending quote to {} channels",
+                failed.len()
+            );
+            record_failed_quote(quote, &failed)?;
+        }
+    }
+    wait_for_webhooks().await;
+    Ok(())
+}
+
+/// Let webhooks still being sent in the background finish before exiting.
+async fn wait_for_webhooks() {
+    let timeout = get_config().shutdown_timeout;
+    if !SEND_BARRIER.wait_idle(timeout).await {
+        warn!(
+            "Webhooks still in progress after {:?}, exiting anyway",
+            timeout
+        );
+    }
+}
+
+/// Log to stdout, filtered by `RUST_LOG`, and to a daily rotating file in
+/// `log_dir` if configured, filtered by `log_level`.
+///
+/// The returned guard flushes the file logger when dropped.
+fn init_logging() -> Option<tracing_appender::non_blocking::WorkerGuard> {
+    let config = get_config();
+    let json = get_args().json_logs
+        || std::env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+    let console_layer = tracing_subscriber::fmt::layer();
+    let console_layer = if json {
+        console_layer.json().boxed()
+    } else {
+        console_layer.boxed()
+    };
+    let console_layer = console_layer
+        .with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")));
+
+    let (file_layer, guard) = match &config.log_dir {
+        Some(log_dir) => {
+            let appender = tracing_appender::rolling::daily(log_dir, "hellquotes-bot.log");
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false);
+            let layer = if json {
+                layer.json().boxed()
+            } else {
+                layer.boxed()
+            };
+            let layer = layer.with_filter(
+                EnvFilter::try_new(&config.log_level)
+                    .expect("Config: log_level must be a valid filter"),
+            );
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(console_layer)
+        .with(file_layer)
+        .init();
+    guard
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ah::Result<()> {
+    if get_args().version {
+        println!("hellquotes-bot v{}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
+    if get_args().list_commands {
+        print!("{}", list_commands(&commands()));
+        return Ok(());
+    }
+
+    // before logging is set up, since that reads the config too
+    if get_args().dump_config {
+        println!("{}", serde_json::to_string_pretty(get_config())?);
+        return Ok(());
+    }
+
+    if get_args().check_config {
+        config::check_config()?;
+        println!("Config is valid");
+        return Ok(());
+    }
+
+    let _log_guard = init_logging();
+    for deprecation in &get_config().deprecations {
+        warn!("Config: {}", deprecation);
+    }
+
+    if let Some(path) = &get_args().export {
+        let count = export::export(path, get_args().export_format)?;
+        info!("Exported {} quotes to {}", count, path);
+        return Ok(());
+    }
+
+    if let Some(id) = get_args().replay_since {
+        return replay_since(id).await;
+    }
+
+    if get_args().once {
+        return run_once().await;
+    }
+
+    if get_args().migrate {
+        // the database is migrated as soon as it's opened
+        get_db()?;
+        info!("Database is up to date");
+        return Ok(());
+    }
 
     let shutdown = Shutdown::new();
     let shutdown_ = shutdown.clone();
-    // Spawn a task to wait for CTRL+C and trigger a shutdown.
+    // Spawn a task to wait for CTRL+C or SIGTERM and trigger a shutdown.
     tokio::spawn({
         let shutdown = shutdown.clone();
         async move {
-            if let Err(e) = tokio::signal::ctrl_c().await {
-                error!("Failed to wait for CTRL+C: {}", e);
+            if let Err(e) = shutdown_signal().await {
+                error!("Failed to wait for shutdown signal: {}", e);
                 std::process::exit(1);
             } else {
-                warn!("\nReceived interrupt signal. Shutting down server...");
+                warn!("\nReceived shutdown signal. Shutting down server...");
+                let timeout = get_config().shutdown_timeout;
+                if !SEND_BARRIER.wait_idle(timeout).await {
+                    warn!(
+                        in_flight = SEND_BARRIER.in_flight(),
+                        "Quote sends still in progress after {:?}, shutting down anyway", timeout
+                    );
+                }
                 shutdown.shutdown();
             }
         }
     });
 
+    if get_args().noop_discord {
+        info!("Not connecting to Discord, printing quotes instead.");
+        start_watching(Arc::new(discord::StdoutDiscord), &shutdown)?;
+        shutdown.wait_shutdown_triggered().await;
+        return Ok(());
+    }
+
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![register(), quote(), help()],
+            commands: commands(),
             on_error: |e| Box::pin(on_error(e)),
+            command_check: Some(|ctx| {
+                Box::pin(async move {
+                    Ok(guild_allowed(ctx).await?
+                        && has_permission(ctx).await?
+                        && rate_limit(ctx).await?)
+                })
+            }),
+            event_handler: |ctx, event, framework, data| {
+                Box::pin(event_handler(ctx, event, framework, data))
+            },
             prefix_options: poise::PrefixFrameworkOptions {
-                prefix: Some("~".into()),
+                prefix: Some(get_config().prefix.clone()),
                 edit_tracker: None,
                 case_insensitive_commands: true,
                 ..Default::default()
@@ -329,37 +2501,381 @@ async fn main() -> ah::Result<()> {
         .intents(
             serenity::GatewayIntents::MESSAGE_CONTENT
                 | serenity::GatewayIntents::GUILD_MESSAGES
-                | serenity::GatewayIntents::DIRECT_MESSAGES,
+                | serenity::GatewayIntents::DIRECT_MESSAGES
+                | serenity::GatewayIntents::GUILD_MESSAGE_REACTIONS
+                // privileged, so only asked for when needed
+                | if get_config().auto_delete_credentials {
+                    serenity::GatewayIntents::GUILD_MEMBERS
+                } else {
+                    serenity::GatewayIntents::empty()
+                },
         )
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
-                poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                if get_args().dry_run {
+                    info!("Dry run, not registering commands.");
+                } else {
+                    poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                }
+                check_quotes_channels(&ctx.http).await?;
+                if let Some(presence) = &get_config().presence {
+                    ctx.set_presence(presence.activity(), presence.status).await;
+                }
                 info!("Bot setup complete.");
 
-                let quote_db_path = &get_config().quotes_db_path;
+                let (poll_tx, quote_cache) = start_watching(ctx.http.clone(), &shutdown_)?;
+                if let Some(time) = get_config().daily_summary_time {
+                    let summary_task = watcher::run_daily_summary(ctx.http.clone(), time);
+                    tokio::spawn(shutdown_.wrap_cancel(summary_task));
+                }
 
-                let (poll_tx, poller_task) =
-                    watcher::create_poller(ctx.http.clone(), quote_db_path, shutdown_.clone())?;
-                let poller_task = shutdown_.wrap_vital(poller_task);
-                let poller_task = shutdown_.wrap_cancel(poller_task);
-                tokio::spawn(poller_task);
-
-                let fs_watcher = watcher::configure_fs_watcher(poll_tx.clone(), quote_db_path)?;
-                // de-allocate the watcher when we're done using a never-finishing task
-                let watcher_task = async move {
-                    let _fs_watcher = fs_watcher;
-                    let () = std::future::pending().await;
-                };
-                tokio::spawn(shutdown_.wrap_cancel(watcher_task));
-
-                Ok(Data { poll_tx })
+                Ok(Data {
+                    poll_tx,
+                    command_history: DashMap::new(),
+                    per_command_history: DashMap::new(),
+                    titanic_breaker: Mutex::default(),
+                    quote_cache,
+                })
             })
         });
+    if let Some(addr) = get_config().metrics_bind_addr {
+        let metrics_task = async move {
+            if let Err(e) = metrics::serve(addr).await {
+                error!("Metrics server failed: {}", e);
+            }
+        };
+        tokio::spawn(shutdown.wrap_cancel(metrics_task));
+    }
+
     let bot_run = framework.run();
     let bot_run = shutdown.wrap_vital(shutdown.wrap_cancel(bot_run));
     match bot_run.await {
-        None => return Ok(info!("Main bot loop cancelled by shutdown.")),
-        Some(Err(e)) => return Err(e.into()),
+        None => {
+            info!("Main bot loop cancelled by shutdown.");
+            Ok(())
+        }
+        Some(Err(e)) => Err(e.into()),
         Some(Ok(_)) => unreachable!(), // bot loop never exits
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord::MockHttp;
+    use std::sync::Once;
+
+    /// Configure the bot from the environment, since there's no config file
+    /// in tests.
+    fn init_test_config() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let db_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+            std::env::set_var("QUOTES_TOKEN", "token");
+            std::env::set_var("QUOTES_CHANNEL_ID", "1, 2");
+            std::env::set_var("QUOTES_DB_FILE", db_file.keep().unwrap());
+            let quotes_db_file = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+            std::env::set_var("QUOTES_DB_PATH", quotes_db_file.keep().unwrap());
+            std::env::set_var("QUOTES_CREDENTIALS_KEY", "00".repeat(32));
+        });
+    }
+
+    #[test]
+    fn bot_db_uses_wal() {
+        init_test_config();
+        let conn = get_db().unwrap();
+        let journal_mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        let synchronous: i64 = conn
+            .query_row("PRAGMA synchronous", [], |r| r.get(0))
+            .unwrap();
+        // NORMAL
+        assert_eq!(synchronous, 1);
+    }
+
+    #[tokio::test]
+    async fn send_quote_embeds_quote_in_every_channel() {
+        init_test_config();
+        let http = MockHttp::default();
+        let quote = Quote {
+            id: 7,
+            text: "line one\nline two".to_owned(),
+            tags: Some("tag".to_owned()),
+        };
+        assert!(send_quote(&quote, &http).await.is_empty());
+
+        let sent = http.sent.lock().unwrap();
+        assert_eq!(
+            sent.iter().map(|(channel, _)| *channel).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        for (_, embed) in sent.iter() {
+            assert_eq!(embed.0["title"], "Quote #7");
+            assert_eq!(embed.0["description"], "line one\nline two");
+            assert_eq!(embed.0["url"], "https://blacker.caltech.edu/quotes/?q=7");
+            assert_eq!(embed.0["footer"]["text"], "Tags: tag");
+        }
+    }
+
+    #[test]
+    fn per_minute_limit_allows_limit_runs() {
+        let mut history = VecDeque::new();
+        assert!(check_per_minute(&mut history, 2).is_ok());
+        assert!(check_per_minute(&mut history, 2).is_ok());
+        let remaining = check_per_minute(&mut history, 2).unwrap_err();
+        assert!(remaining <= Duration::from_secs(60));
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn per_minute_limit_of_zero_is_always_limited() {
+        let mut history = VecDeque::new();
+        assert_eq!(
+            check_per_minute(&mut history, 0),
+            Err(Duration::from_secs(60))
+        );
+        assert!(history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_sends_are_recorded_per_channel() {
+        init_test_config();
+        let http = MockHttp {
+            failing: vec![2],
+            ..Default::default()
+        };
+        let quote = Quote {
+            id: 31,
+            text: "half sent".to_owned(),
+            tags: None,
+        };
+        let failed = send_quote(&quote, &http).await;
+        assert_eq!(failed, [serenity::ChannelId(2)]);
+        assert_eq!(
+            http.sent
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(channel, _)| *channel)
+                .collect::<Vec<_>>(),
+            [1]
+        );
+
+        record_failed_quote(&quote, &failed).unwrap();
+        let channels: Vec<u64> = {
+            let conn = get_db().unwrap();
+            let mut stmt = conn
+                .prepare("SELECT channel_id FROM failed_quotes WHERE id = ?1")
+                .unwrap();
+            let channels = stmt
+                .query_map([quote.id], |r| r.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            channels
+        };
+        assert_eq!(channels, [2]);
+    }
+
+    #[tokio::test]
+    async fn submitter_is_told_once_their_quote_is_posted() {
+        init_test_config();
+        record_pending_submission(42, "pending quote").unwrap();
+        let http = MockHttp::default();
+        let quote = |id: i64| Quote {
+            id,
+            text: "pending quote".to_owned(),
+            tags: None,
+        };
+        notify_submitter(&quote(9), &http).await;
+        // the submission is used up, so a repeat of the text isn't announced
+        notify_submitter(&quote(10), &http).await;
+        assert_eq!(
+            *http.dms.lock().unwrap(),
+            [(42, "Your quote #9 has been posted!".to_owned())]
+        );
+    }
+
+    #[tokio::test]
+    async fn polled_quotes_are_sent_once() {
+        init_test_config();
+        let seen_file = tempfile::NamedTempFile::new().unwrap();
+        let seen_path = seen_file.path().to_str().unwrap();
+        let quotes_file = tempfile::NamedTempFile::new().unwrap();
+        let quotes_path = quotes_file.path().to_str().unwrap();
+        let quotes_db = sql::Connection::open(quotes_path).unwrap();
+        quotes_db
+            .execute(
+                "CREATE TABLE quotes (id INTEGER PRIMARY KEY, quote TEXT NOT NULL, tags TEXT)",
+                [],
+            )
+            .unwrap();
+        let insert = |ids: std::ops::RangeInclusive<i64>| {
+            for id in ids {
+                quotes_db
+                    .execute(
+                        "INSERT INTO quotes (id, quote) VALUES (?1, ?2)",
+                        sql::params![id, format!("quote {}", id)],
+                    )
+                    .unwrap();
+            }
+        };
+        let http = MockHttp::default();
+        let poll_and_send = || async {
+            let quotes = watcher::poll_once(seen_path, quotes_path).unwrap();
+            for quote in &quotes {
+                assert!(send_quote(quote, &http).await.is_empty());
+            }
+            quotes
+                .into_iter()
+                .map(|quote| (quote.id, quote.text))
+                .collect::<Vec<_>>()
+        };
+        let expected = |ids: std::ops::RangeInclusive<i64>| {
+            ids.map(|id| (id, format!("quote {}", id)))
+                .collect::<Vec<_>>()
+        };
+
+        // the first poll only marks what's already there as seen
+        assert_eq!(poll_and_send().await, []);
+        insert(1..=5);
+        assert_eq!(poll_and_send().await, expected(1..=5));
+        insert(6..=8);
+        assert_eq!(poll_and_send().await, expected(6..=8));
+        assert_eq!(poll_and_send().await, []);
+
+        // each quote once in each of the two channels
+        let sent = http.sent.lock().unwrap();
+        let titles: Vec<_> = sent
+            .iter()
+            .map(|(_, embed)| embed.0["title"].as_str().unwrap().to_owned())
+            .collect();
+        let expected_titles: Vec<_> = (1..=8)
+            .flat_map(|id| [format!("Quote #{}", id), format!("Quote #{}", id)])
+            .collect();
+        assert_eq!(titles, expected_titles);
+    }
+
+    #[test]
+    fn quote_url_links_to_titanic() {
+        let quote = Quote {
+            id: 42,
+            text: "text".to_owned(),
+            tags: None,
+        };
+        assert_eq!(
+            quote.url_on("https://blacker.caltech.edu/quotes/"),
+            "https://blacker.caltech.edu/quotes/?q=42"
+        );
+    }
+
+    #[test]
+    fn truncate_str_keeps_short_ascii() {
+        assert_eq!(truncate_str("hello", 5), "hello");
+        assert_eq!(truncate_str("hello", 10), "hello");
+    }
+
+    #[test]
+    fn truncate_str_cuts_long_ascii() {
+        assert_eq!(truncate_str("hello world", 5), "hello");
+    }
+
+    #[test]
+    fn truncate_str_counts_chars_not_bytes() {
+        // three bytes each
+        assert_eq!(truncate_str("日本語のテキスト", 3), "日本語");
+        assert_eq!(truncate_str("日本語", 3), "日本語");
+    }
+
+    #[test]
+    fn truncate_str_cuts_at_multibyte_boundary() {
+        // the cut falls exactly where "é" starts
+        assert_eq!(truncate_str("abcé", 3), "abc");
+        assert_eq!(truncate_str("aé😀b", 2), "aé");
+        assert_eq!(truncate_str("aé😀b", 3), "aé😀");
+    }
+
+    #[test]
+    fn truncate_str_handles_empty_string() {
+        assert_eq!(truncate_str("", 0), "");
+        assert_eq!(truncate_str("", 5), "");
+        assert_eq!(truncate_str("abc", 0), "");
+    }
+
+    #[test]
+    fn markdown_safe_keeps_short_text() {
+        assert_eq!(truncate_str_markdown_safe("**bold", 10), "**bold");
+    }
+
+    #[test]
+    fn markdown_safe_cuts_before_an_open_span() {
+        assert_eq!(
+            truncate_str_markdown_safe("plain **bold text** end", 12),
+            "plain "
+        );
+        assert_eq!(
+            truncate_str_markdown_safe("a `code` b `more`", 13),
+            "a `code` b "
+        );
+        // closed spans don't move the cut
+        assert_eq!(truncate_str_markdown_safe("*one* and more", 9), "*one* and");
+    }
+
+    #[test]
+    fn markdown_safe_ignores_literal_markers() {
+        assert_eq!(
+            truncate_str_markdown_safe("call snake_case_name here", 20),
+            "call snake_case_name"
+        );
+        assert_eq!(
+            truncate_str_markdown_safe("5 * 3 is fifteen", 10),
+            "5 * 3 is f"
+        );
+        // markers inside code are just text
+        assert_eq!(truncate_str_markdown_safe("`a*b` and c", 8), "`a*b` an");
+    }
+
+    #[test]
+    fn markdown_safe_limits_how_far_back_it_cuts() {
+        let text = format!("*{}", "x".repeat(300));
+        assert_eq!(
+            truncate_str_markdown_safe(&text, 250),
+            truncate_str(&text, 250)
+        );
+    }
+
+    #[test]
+    fn escaped_like_patterns_match_literally() {
+        let conn = sql::Connection::open_in_memory().unwrap();
+        let matches = |text: &str, query: &str| -> bool {
+            conn.query_row(
+                "SELECT ?1 LIKE ?2 ESCAPE '\\'",
+                [text, &format!("%{}%", escape_like(query))],
+                |r| r.get(0),
+            )
+            .unwrap()
+        };
+        assert!(matches("100% sure", "100%"));
+        assert!(!matches("100 sure", "100%"));
+        assert!(matches("snake_case", "e_c"));
+        assert!(!matches("snakeXcase", "e_c"));
+        assert!(matches("a\\b", "a\\b"));
+    }
+
+    #[test]
+    fn quote_displays_id_text_and_tags() {
+        let quote = Quote {
+            id: 7,
+            text: "hello world".to_owned(),
+            tags: Some("tag1 tag2".to_owned()),
+        };
+        assert_eq!(quote.to_string(), "[#7] \"hello world\" (tags: tag1 tag2)");
+        let untagged = Quote {
+            tags: None,
+            ..quote
+        };
+        assert_eq!(untagged.to_string(), "[#7] \"hello world\"");
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow as ah;
+use axum::{http::header, response::IntoResponse, routing::get, Json, Router};
+use once_cell::sync::Lazy;
+use prometheus::{Encoder, Gauge, IntCounter, Registry, TextEncoder};
+use std::{collections::VecDeque, net::SocketAddr};
+use tracing::info;
+
+use crate::watcher::PollMetrics;
+
+/// Counters exposed on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub quotes_submitted: IntCounter,
+    pub discord_sends_succeeded: IntCounter,
+    pub discord_sends_failed: IntCounter,
+    pub poller_checks: IntCounter,
+    poll_duration_ms_avg: Gauge,
+    poll_new_quotes_avg: Gauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounter::new(name, help).expect("invalid metric");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("duplicate metric");
+            counter
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge = Gauge::new(name, help).expect("invalid metric");
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("duplicate metric");
+            gauge
+        };
+        Self {
+            quotes_submitted: counter(
+                "quotes_submitted_total",
+                "Quotes successfully submitted to titanic",
+            ),
+            discord_sends_succeeded: counter(
+                "discord_sends_succeeded_total",
+                "Quotes sent to a quotes channel",
+            ),
+            discord_sends_failed: counter(
+                "discord_sends_failed_total",
+                "Quotes that couldn't be sent to a quotes channel",
+            ),
+            poller_checks: counter(
+                "poller_checks_total",
+                "Checks of the quotes database for new quotes",
+            ),
+            poll_duration_ms_avg: gauge(
+                "poll_duration_ms_avg",
+                "Average time the recent checks of the quotes database took",
+            ),
+            poll_new_quotes_avg: gauge(
+                "poll_new_quotes_avg",
+                "Average new quotes found by the recent checks of the quotes database",
+            ),
+            registry,
+        }
+    }
+
+    /// Update the poll averages from the poller's recent polls.
+    pub fn observe_polls(&self, polls: &VecDeque<PollMetrics>) {
+        if polls.is_empty() {
+            return;
+        }
+        let count = polls.len() as f64;
+        let duration: u64 = polls.iter().map(|poll| poll.duration_ms).sum();
+        let new_quotes: usize = polls.iter().map(|poll| poll.new_quotes).sum();
+        self.poll_duration_ms_avg.set(duration as f64 / count);
+        self.poll_new_quotes_avg.set(new_quotes as f64 / count);
+    }
+}
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// Serve `/health` and `/metrics` on `addr` until cancelled.
+pub async fn serve(addr: SocketAddr) -> ah::Result<()> {
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics));
+    info!(%addr, "Serving metrics");
+    axum::Server::try_bind(&addr)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+async fn health() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+async fn metrics() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&METRICS.registry.gather(), &mut body)
+        .expect("Couldn't encode metrics");
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        body,
+    )
+}
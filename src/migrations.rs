@@ -0,0 +1,148 @@
+use rusqlite as sql;
+use tracing::info;
+
+/// Schema migrations for the bot database, applied in order. A migration's id
+/// is its position in this list, counting from 1, so only ever append.
+///
+/// Migrations that need the default server name can read it from
+/// `temp.migration_settings`, which `run_migrations` fills in.
+const MIGRATIONS: &[&str] = &[
+    // 1: the original credentials table
+    "CREATE TABLE IF NOT EXISTS credentials (
+           discord_id                INTEGER PRIMARY KEY,
+           auth_user                 TEXT,
+           auth_pass                 TEXT
+           );",
+    // 2: plaintext rows get upgraded the next time they're used
+    "ALTER TABLE credentials ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;",
+    // 3: existing credentials belong to the default server
+    "CREATE TABLE credentials_new (
+           discord_id                INTEGER,
+           server                    TEXT NOT NULL,
+           auth_user                 TEXT,
+           auth_pass                 TEXT,
+           encrypted                 INTEGER NOT NULL DEFAULT 0,
+           PRIMARY KEY (discord_id, server)
+           );
+     INSERT INTO credentials_new
+     SELECT discord_id, (SELECT default_server FROM temp.migration_settings),
+            auth_user, auth_pass, encrypted
+     FROM credentials;
+     DROP TABLE credentials;
+     ALTER TABLE credentials_new RENAME TO credentials;",
+    // 4
+    "CREATE TABLE IF NOT EXISTS failed_quotes (
+           id                        INTEGER,
+           text                      TEXT,
+           tags                      TEXT,
+           failed_at                 TEXT
+           );",
+    // 5: keyed on the channel as well, since a quote is sent to every quotes channel
+    "CREATE TABLE IF NOT EXISTS sent_messages (
+           quote_id                  INTEGER,
+           message_id                INTEGER,
+           channel_id                INTEGER,
+           sent_at                   TEXT,
+           PRIMARY KEY (quote_id, channel_id)
+           );",
+    // 6
+    "CREATE TABLE IF NOT EXISTS quote_ratings (
+           quote_id                  INTEGER PRIMARY KEY,
+           upvotes                   INTEGER NOT NULL DEFAULT 0,
+           downvotes                 INTEGER NOT NULL DEFAULT 0
+           );",
+    // 7: null unless create_threads was on
+    "ALTER TABLE sent_messages ADD COLUMN thread_id INTEGER;",
+    // 8: the newest quote included in a daily summary
+    "CREATE TABLE IF NOT EXISTS daily_summary (
+           last_quote_id             INTEGER
+           );",
+    // 9: every /quote attempt. quote_id stays null, since titanic doesn't say
+    // which id it gave a new quote
+    "CREATE TABLE IF NOT EXISTS submissions (
+           id                        INTEGER PRIMARY KEY AUTOINCREMENT,
+           discord_id                INTEGER,
+           quote_id                  INTEGER,
+           submitted_at              TEXT,
+           success                   INTEGER
+           );",
+    // 10: quotes submitted with /quote that haven't been posted yet, matched
+    // to posted quotes by text, since their id isn't known
+    "CREATE TABLE IF NOT EXISTS pending_submissions (
+           id                        INTEGER PRIMARY KEY AUTOINCREMENT,
+           discord_id                INTEGER NOT NULL,
+           quote                     TEXT NOT NULL,
+           submitted_at              TEXT NOT NULL
+           );",
+    // 11: which channel a send failed in. Older rows have none, meaning all
+    // of them
+    "ALTER TABLE failed_quotes ADD COLUMN channel_id INTEGER;",
+];
+
+/// Bring the bot database up to date, returning how many migrations were applied.
+pub fn run_migrations(conn: &sql::Connection, default_server: &str) -> sql::Result<usize> {
+    let tracked: bool = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'migrations'",
+        [],
+        |row| row.get(0),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS migrations (
+               id                        INTEGER PRIMARY KEY,
+               applied_at                TEXT
+               )",
+        [],
+    )?;
+    if !tracked {
+        baseline(conn)?;
+    }
+    conn.execute(
+        "CREATE TEMP TABLE IF NOT EXISTS migration_settings (default_server TEXT)",
+        [],
+    )?;
+    conn.execute("DELETE FROM temp.migration_settings", [])?;
+    conn.execute(
+        "INSERT INTO temp.migration_settings VALUES (?1)",
+        [default_server],
+    )?;
+
+    let applied: i64 = conn.query_row("SELECT IFNULL(MAX(id), 0) FROM migrations", [], |row| {
+        row.get(0)
+    })?;
+    let mut count = 0;
+    for (id, migration) in (1..).zip(MIGRATIONS).skip(applied as usize) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute("INSERT INTO migrations VALUES (?1, datetime('now'))", [id])?;
+        tx.commit()?;
+        info!("Applied database migration {}", id);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Databases from before migrations were tracked had their credentials
+/// table changed in place, so work out which of those changes they have.
+/// The later migrations are safe to rerun.
+fn baseline(conn: &sql::Connection) -> sql::Result<()> {
+    let has_column = |column: &str| -> sql::Result<bool> {
+        conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('credentials') WHERE name = ?1",
+            [column],
+            |row| row.get(0),
+        )
+    };
+    let applied = if has_column("server")? {
+        3
+    } else if has_column("encrypted")? {
+        2
+    } else if has_column("discord_id")? {
+        1
+    } else {
+        0
+    };
+    for id in 1..=applied {
+        conn.execute("INSERT INTO migrations VALUES (?1, NULL)", [id])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// Counts operations that shouldn't be cut off by a shutdown, so the shutdown
+/// handler can wait for them to finish.
+#[derive(Default)]
+pub struct ShutdownBarrier {
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/// Marks an operation as in flight until dropped.
+pub struct BarrierGuard<'a> {
+    barrier: &'a ShutdownBarrier,
+}
+
+impl ShutdownBarrier {
+    pub fn enter(&self) -> BarrierGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        BarrierGuard { barrier: self }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Wait for everything in flight to finish, giving up after `timeout`.
+    /// Returns whether the barrier became idle.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                // register before checking, so a guard dropped in between
+                // still wakes us
+                let idle = self.idle.notified();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+impl Drop for BarrierGuard<'_> {
+    fn drop(&mut self) {
+        if self.barrier.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.barrier.idle.notify_waiters();
+        }
+    }
+}
@@ -1,133 +1,1134 @@
 use anyhow as ah;
 use async_shutdown::Shutdown;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use once_cell::sync::Lazy;
 use poise::serenity_prelude::Http;
 use rusqlite as sql;
-use std::{ops::Deref, time::Duration};
-use tokio::sync::mpsc;
-use tracing::{info, trace};
+use sql::OptionalExtension;
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, info_span, trace, warn, Instrument};
 
-use crate::{send_quote, Quote};
+use crate::{
+    cache::QuoteCache, discord::DiscordHttp, get_args, get_config, get_quotes_db, metrics::METRICS,
+    notify_submitter, panic_message, record_failed_quote, send_quote, truncate_str, Quote,
+};
+
+/// Bumped whenever the quotes db is reopened, so that connections attached
+/// to the old file know to re-attach, and the fs watcher to watch the new one.
+static QUOTES_DB_GENERATION: Lazy<watch::Sender<u64>> = Lazy::new(|| watch::channel(0).0);
+
+/// Attach the quotes db to `conn` as `quotes`, unless it's been attached since
+/// the last reopen. Pooled connections keep their attachment between uses,
+/// so this re-attaches one left pointing at a replaced file.
+pub fn attach_current(conn: &sql::Connection, db_path: &str) -> sql::Result<()> {
+    let generation = *QUOTES_DB_GENERATION.borrow();
+    conn.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS quotes_generation (generation INTEGER NOT NULL)",
+    )?;
+    let attached: Option<u64> = conn
+        .query_row("SELECT generation FROM temp.quotes_generation", [], |r| {
+            r.get(0)
+        })
+        .optional()?;
+    match attached {
+        Some(attached) if attached == generation => return Ok(()),
+        Some(_) => {
+            conn.execute("DETACH DATABASE quotes", [])?;
+        }
+        None => {}
+    }
+    QuoteWatcher::attach(conn, db_path)?;
+    conn.execute("DELETE FROM temp.quotes_generation", [])?;
+    conn.execute(
+        "INSERT INTO temp.quotes_generation (generation) VALUES (?1)",
+        [generation],
+    )?;
+    Ok(())
+}
+
+/// Requests handled by the db_watcher thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatcherRequest {
+    /// Check for new quotes.
+    Poll,
+    /// Forget which quotes have been seen, so they're all sent again.
+    Reset,
+    /// Re-attach the quotes db, after it's been replaced.
+    Reopen,
+}
 
 pub struct QuoteWatcher {
     db_conn: sql::Connection,
+    schema: QuoteSchema,
+    /// The most recent polls, oldest first.
+    stats: VecDeque<PollMetrics>,
+    stats_capacity: usize,
+}
+
+/// How a single poll went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollMetrics {
+    pub duration_ms: u64,
+    pub new_quotes: usize,
+    pub total_seen: usize,
+}
+
+/// Names the quotes table's columns have gone by, most recent first.
+const ID_COLUMNS: &[&str] = &["id", "quote_id"];
+const TEXT_COLUMNS: &[&str] = &["quote", "text", "body"];
+const TAGS_COLUMNS: &[&str] = &["tags", "tag"];
+const AUTHOR_COLUMNS: &[&str] = &["author"];
+
+/// Which columns of the quotes table hold a quote's id, text and tags, so
+/// that the watcher works with both the legacy and current titanic schemas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuoteSchema {
+    id: &'static str,
+    text: &'static str,
+    /// Older quotes dbs have no tags.
+    tags: Option<&'static str>,
+    /// Not every deployment records who submitted a quote.
+    author: Option<&'static str>,
 }
 
-// TODO: this could/should use the timestamp of the created quote with and an
-// index on that field to avoid a full table scan. That's a little unreliable
-// in sqlite because there's no actual date type, however. For now, this is
-// slow, but general, and will work with any changes to the quote db schema.
+impl QuoteSchema {
+    /// Look at the columns of the attached quotes db's quotes table.
+    pub fn detect(db_conn: &sql::Connection) -> ah::Result<Self> {
+        let columns = {
+            let mut stmt =
+                db_conn.prepare("SELECT name FROM pragma_table_info('quotes', 'quotes')")?;
+            let columns = stmt
+                .query_map([], |r| r.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            columns
+        };
+        if columns.is_empty() {
+            ah::bail!("The quotes database has no quotes table");
+        }
+        let find = |aliases: &[&'static str]| {
+            aliases
+                .iter()
+                .copied()
+                .find(|alias| columns.iter().any(|c| c.eq_ignore_ascii_case(alias)))
+        };
+        let missing = |aliases: &[&str]| {
+            ah::anyhow!(
+                "The quotes table has none of the columns {:?}, only {:?}",
+                aliases,
+                columns
+            )
+        };
+        Ok(Self {
+            id: find(ID_COLUMNS).ok_or_else(|| missing(ID_COLUMNS))?,
+            text: find(TEXT_COLUMNS).ok_or_else(|| missing(TEXT_COLUMNS))?,
+            tags: find(TAGS_COLUMNS),
+            author: find(AUTHOR_COLUMNS),
+        })
+    }
+
+    /// The quotes table with its columns renamed to `id, quote, tags`, in the
+    /// order `Quote::from_row` expects, followed by `extra_columns` as they are.
+    /// Use it in place of `quotes.quotes`.
+    pub fn table_with(&self, extra_columns: &[&str]) -> String {
+        let extra: String = extra_columns
+            .iter()
+            .map(|column| format!(", {}", column))
+            .collect();
+        format!(
+            "(SELECT {} AS id, {} AS quote, {} AS tags{} FROM quotes.quotes)",
+            self.id,
+            self.text,
+            self.tags.unwrap_or("NULL"),
+            extra
+        )
+    }
+
+    pub fn table(&self) -> String {
+        self.table_with(&[])
+    }
+
+    /// `table` with the quote's author after its tags as `author`, if the
+    /// quotes db records authors.
+    pub fn table_with_author(&self) -> Option<String> {
+        let author = format!("{} AS author", self.author?);
+        Some(self.table_with(&[&author]))
+    }
+
+    pub fn has_tags(&self) -> bool {
+        self.tags.is_some()
+    }
+
+    /// Select `id, quote, tags` followed by `filter`.
+    fn select(&self, filter: &str) -> String {
+        format!("SELECT * FROM {} {}", self.table(), filter)
+    }
+}
+
+// Polling only reads quotes with ids above the newest seen, rather than
+// checking every quote against the seen table, which relies on titanic's ids
+// only ever going up. See `bench_poll` for how much that saves.
 impl QuoteWatcher {
-    fn new(db_path: &str) -> ah::Result<Self> {
-        let db_conn = sql::Connection::open_in_memory()?;
-        // attach quotes db as read-only
+    /// `seen_db_path` is the bot's own database, where seen quotes are kept
+    /// so that quotes added while the bot is down get sent after a restart.
+    pub fn new(seen_db_path: &str, db_path: &str) -> ah::Result<Self> {
+        let db_conn = sql::Connection::open(seen_db_path)?;
+        // the bot db is already in WAL mode from get_db; keep the temporary
+        // tables and indices of the seen quotes query off disk
+        db_conn.execute_batch("PRAGMA temp_store=MEMORY;")?;
+        Self::attach(&db_conn, db_path)?;
+        let schema = QuoteSchema::detect(&db_conn)?;
+        let first_run: bool = db_conn.query_row(
+            "SELECT COUNT(*) = 0 FROM main.sqlite_master WHERE type = 'table' AND name = 'seen_quotes'",
+            [],
+            |r| r.get(0),
+        )?;
+        db_conn.execute(
+            "CREATE TABLE IF NOT EXISTS main.seen_quotes (id INTEGER PRIMARY KEY)",
+            [],
+        )?;
+        // initialize with existing quotes, rather than broadcasting the whole
+        // history the first time the bot is run
+        if first_run {
+            Self::update_seen(&db_conn, &schema)?;
+        }
+        Ok(Self {
+            db_conn,
+            schema,
+            stats: VecDeque::new(),
+            stats_capacity: 100,
+        })
+    }
+
+    /// Keep metrics for the last `capacity` polls, rather than 100.
+    pub fn with_stats_capacity(mut self, capacity: usize) -> Self {
+        self.stats_capacity = capacity;
+        // keep the newest
+        let excess = self.stats.len().saturating_sub(capacity);
+        self.stats.drain(..excess);
+        self
+    }
+
+    /// Metrics for the most recent polls, oldest first.
+    pub fn stats(&self) -> &VecDeque<PollMetrics> {
+        &self.stats
+    }
+
+    /// Attach the quotes db as read-only.
+    fn attach(db_conn: &sql::Connection, db_path: &str) -> sql::Result<()> {
         let ro_uri = format!("file:{}?mode=ro", db_path);
         db_conn.execute("ATTACH DATABASE ?1 as quotes", [ro_uri])?;
-        db_conn.execute("CREATE TABLE main.seen_quotes (id INTEGER PRIMARY KEY)", [])?;
-        // initialize with existing quotes
-        Self::update_seen(&db_conn)?;
-        Ok(Self { db_conn })
+        Ok(())
     }
 
-    fn update_seen(db_conn: &sql::Connection) -> sql::Result<usize> {
+    /// Detach and re-attach the quotes db, for when the file has been
+    /// replaced, e.g. by a restore from backup. Everything in the new file is
+    /// marked seen, rather than resending whatever it has that the old one
+    /// didn't. Pooled connections re-attach the next time they're used; see
+    /// `attach_current`.
+    pub fn reopen(&mut self, db_path: &str) -> ah::Result<()> {
+        self.db_conn.execute("DETACH DATABASE quotes", [])?;
+        Self::attach(&self.db_conn, db_path)?;
+        self.schema = QuoteSchema::detect(&self.db_conn)?;
+        Self::update_seen(&self.db_conn, &self.schema)?;
+        QUOTES_DB_GENERATION.send_modify(|generation| *generation += 1);
+        Ok(())
+    }
+
+    fn update_seen(db_conn: &sql::Connection, schema: &QuoteSchema) -> sql::Result<usize> {
         db_conn.execute(
-            "
-        INSERT OR REPLACE INTO main.seen_quotes SELECT id from quotes.quotes",
+            &format!(
+                "INSERT OR REPLACE INTO main.seen_quotes SELECT {} FROM quotes.quotes",
+                schema.id
+            ),
             [],
         )
     }
 
+    /// Forget all seen quotes. The next poll sends every quote again.
+    pub fn reset(&mut self) -> ah::Result<()> {
+        self.db_conn.execute("DELETE FROM main.seen_quotes", [])?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
     fn get_new_and_update_seen(&mut self) -> ah::Result<impl Iterator<Item = Quote>> {
+        let start = Instant::now();
+        // only this thread marks quotes seen, so this can't change before the
+        // transaction starts
+        let latest = self.latest_id()?;
         let tx = self.db_conn.transaction()?;
+        let new = Self::take_since(&tx, &self.schema, latest.unwrap_or(i64::MIN))?;
+        let total_seen = tx.query_row("SELECT COUNT(*) FROM main.seen_quotes", [], |r| r.get(0))?;
+        tx.commit()?;
+
+        if self.stats_capacity > 0 {
+            if self.stats.len() >= self.stats_capacity {
+                self.stats.pop_front();
+            }
+            self.stats.push_back(PollMetrics {
+                duration_ms: start.elapsed().as_millis() as u64,
+                new_quotes: new.len(),
+                total_seen,
+            });
+        }
+        Ok(new.into_iter())
+    }
+
+    /// Quotes with ids greater than `min_id`, marking them seen, whether or
+    /// not they'd been seen before.
+    pub fn since(&mut self, min_id: i64) -> ah::Result<impl Iterator<Item = Quote>> {
+        let tx = self.db_conn.transaction()?;
+        let new = Self::take_since(&tx, &self.schema, min_id)?;
+        tx.commit()?;
+        Ok(new.into_iter())
+    }
+
+    fn take_since(
+        db_conn: &sql::Connection,
+        schema: &QuoteSchema,
+        min_id: i64,
+    ) -> sql::Result<Vec<Quote>> {
         let new = {
-            let mut stmt = tx.prepare(
-            "SELECT id, quote, tags FROM quotes.quotes WHERE id NOT IN (SELECT id FROM main.seen_quotes)")?;
+            let mut stmt = db_conn.prepare(&schema.select("WHERE id > ?1 ORDER BY id"))?;
             let results = stmt
-                .query_map([], |r| {
-                    Ok(Quote {
-                        id: r.get(0)?,
-                        text: r.get(1)?,
-                        tags: r.get(2)?,
-                    })
-                })?
+                .query_map([min_id], Quote::from_row)?
                 .collect::<Result<Vec<Quote>, _>>()?;
             results
         };
-        Self::update_seen(tx.deref())?;
-        tx.commit()?;
-        Ok(new.into_iter())
+        db_conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO main.seen_quotes SELECT {0} FROM quotes.quotes WHERE {0} > ?1",
+                schema.id
+            ),
+            [min_id],
+        )?;
+        Ok(new)
     }
+
+    /// See `quote_count`.
+    pub fn quote_count(&self) -> ah::Result<(usize, usize)> {
+        quote_count(&self.db_conn)
+    }
+
+    /// See `latest_id`.
+    pub fn latest_id(&self) -> ah::Result<Option<i64>> {
+        latest_id(&self.db_conn)
+    }
+
+    /// See `is_new`.
+    // commands use the free function on a pooled connection
+    #[allow(dead_code)]
+    pub fn is_new(&self, id: i64) -> ah::Result<bool> {
+        is_new(&self.db_conn, id)
+    }
+}
+
+// Read-only queries, for commands to run on a pooled connection from
+// `get_quotes_db` rather than opening a watcher of their own.
+
+/// The number of quotes marked seen, and the number in the quotes db.
+pub fn quote_count(conn: &sql::Connection) -> ah::Result<(usize, usize)> {
+    Ok(conn.query_row(
+        "SELECT (SELECT COUNT(*) FROM main.seen_quotes), (SELECT COUNT(*) FROM quotes.quotes)",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?)
+}
+
+/// The id of the newest quote marked seen.
+pub fn latest_id(conn: &sql::Connection) -> ah::Result<Option<i64>> {
+    Ok(conn.query_row("SELECT MAX(id) FROM main.seen_quotes", [], |r| r.get(0))?)
+}
+
+/// Quotes with ids greater than `start_id`, up to and including `end_id`.
+/// Nothing is marked seen, so this is safe for display-only features.
+pub fn get_range(conn: &sql::Connection, start_id: i64, end_id: i64) -> ah::Result<Vec<Quote>> {
+    let mut stmt =
+        conn.prepare(&QuoteSchema::detect(conn)?.select("WHERE id > ?1 AND id <= ?2 ORDER BY id"))?;
+    let quotes = stmt
+        .query_map([start_id, end_id], Quote::from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(quotes)
+}
+
+/// Whether quote `id` hasn't been seen yet.
+pub fn is_new(conn: &sql::Connection, id: i64) -> ah::Result<bool> {
+    Ok(conn.query_row(
+        "SELECT COUNT(*) = 0 FROM main.seen_quotes WHERE id = ?1",
+        [id],
+        |r| r.get(0),
+    )?)
 }
 
-#[allow(dead_code)]
-pub async fn send_timed_checks(sender: mpsc::Sender<()>, dur: Duration) {
+/// Room for poll requests waiting on the db_watcher thread. Since any check
+/// catches all changes, if there's an outstanding check when a poll request
+/// is sent, the originating change is guaranteed to be handled anyway, so a
+/// full queue can safely drop requests. That needs no more than 2 slots; 3
+/// to be safe.
+const POLL_CHANNEL_CAPACITY: usize = 3;
+
+/// Request a poll every `dur`, in case the fs watcher misses an event.
+pub async fn send_timed_checks(sender: mpsc::Sender<WatcherRequest>, dur: Duration) {
     let mut interval = tokio::time::interval(dur);
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     loop {
         interval.tick().await;
-        sender.try_send(()).ok();
+        sender.try_send(WatcherRequest::Poll).ok();
+    }
+}
+
+/// Request a reopen of the quotes db on every SIGUSR1, for after it's been
+/// replaced.
+#[cfg(unix)]
+pub async fn reopen_on_sigusr1(sender: mpsc::Sender<WatcherRequest>) -> ah::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut user_defined1 = signal(SignalKind::user_defined1())?;
+    while user_defined1.recv().await.is_some() {
+        info!("Got SIGUSR1, reopening the quotes db");
+        sender.send(WatcherRequest::Reopen).await?;
     }
+    Ok(())
 }
 
+/// Request a poll when the quotes db changes. A sqlite commit touches the
+/// file several times in quick succession, and its last write may be what
+/// makes the quote visible, so the poll waits until the file has been quiet
+/// for `debounce`. The returned future owns the fs watcher and never finishes.
 pub fn configure_fs_watcher(
-    sender: mpsc::Sender<()>,
+    sender: mpsc::Sender<WatcherRequest>,
     db_path: &str,
-) -> ah::Result<RecommendedWatcher> {
+    debounce: Duration,
+) -> ah::Result<impl std::future::Future<Output = ()> + Send> {
+    let (change_tx, mut change_rx) = mpsc::unbounded_channel();
     let mut watcher = RecommendedWatcher::new(
-        // No error handling: a full queue means a flush is already pending,
-        // a dropped queue means the app is shutting down.
         move |_res| {
-            info!("Notification triggered");
-            sender.try_send(()).ok();
+            change_tx.send(()).ok();
         },
         notify::Config::default(),
     )?;
-    watcher.watch(db_path.as_ref(), RecursiveMode::NonRecursive)?;
-    Ok(watcher)
+    let db_path = std::path::PathBuf::from(db_path);
+    watcher.watch(&db_path, RecursiveMode::NonRecursive)?;
+    let mut reopened = QUOTES_DB_GENERATION.subscribe();
+
+    Ok(async move {
+        loop {
+            tokio::select! {
+                change = change_rx.recv() => {
+                    if change.is_none() {
+                        return;
+                    }
+                    loop {
+                        match tokio::time::timeout(debounce, change_rx.recv()).await {
+                            Ok(Some(())) => trace!("Notification debounced"),
+                            Ok(None) => return,
+                            Err(_) => break,
+                        }
+                    }
+                    info!("Notification triggered");
+                    // No error handling: a full queue means a flush is already
+                    // pending, a dropped queue means the app is shutting down.
+                    sender.try_send(WatcherRequest::Poll).ok();
+                }
+                Ok(()) = reopened.changed() => {
+                    // a replacement renamed over the old file leaves the watch
+                    // on the old inode
+                    watcher.unwatch(&db_path).ok();
+                    match watcher.watch(&db_path, RecursiveMode::NonRecursive) {
+                        Ok(()) => info!("Watching the reopened quotes db"),
+                        Err(e) => error!("Couldn't watch the reopened quotes db: {}", e),
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Check for new quotes a single time, marking them seen.
+pub fn poll_once(seen_db_path: &str, db_path: &str) -> ah::Result<Vec<Quote>> {
+    let mut watcher = QuoteWatcher::new(seen_db_path, db_path)?;
+    METRICS.poller_checks.inc();
+    Ok(watcher.get_new_and_update_seen()?.collect())
 }
 
 pub fn create_poller(
-    disc_http: std::sync::Arc<Http>,
+    disc_http: std::sync::Arc<dyn DiscordHttp>,
+    quote_cache: std::sync::Arc<QuoteCache>,
+    seen_db_path: &str,
     db_path: &str,
+    poll_interval: Duration,
     shutdown: Shutdown,
 ) -> ah::Result<(
-    mpsc::Sender<()>,
+    mpsc::Sender<WatcherRequest>,
+    PollerHealth,
     impl std::future::Future<Output = ()> + Send,
 )> {
-    // Since any check catches all changes, if there's an outstanding change when a poll
-    // request is sent, the originating request is guaranteed to be handled anyway.
-    // Thus, we don't need more than 2 slots, 3 to be safe.
-    let (notify_tx, mut notify_rx) = mpsc::channel(3);
+    let (notify_tx, mut notify_rx) = mpsc::channel(POLL_CHANNEL_CAPACITY);
 
     // the task is going to run on a separate thread to avoid !Sync issues.
+    // Unbounded, so the db_watcher thread never blocks on a slow Discord: a
+    // burst of quotes is at most the size of the quotes db, and every one
+    // of them has already been marked seen, so none may be dropped.
     let (quote_tx, mut quote_rx) = mpsc::unbounded_channel();
+    // when the last check finished and when the current send started, for
+    // the watchdog
+    let (health_tx, health_rx) = watch::channel(Instant::now());
+    let (send_started_tx, send_started_rx) = watch::channel(None);
     let poller_token = shutdown.vital_token();
+    let poller_shutdown = shutdown.clone();
+    let seen_db_path = seen_db_path.to_owned();
     let db_path = db_path.to_owned();
+    let reopen_cache = quote_cache.clone();
     std::thread::Builder::new()
         .name("db_watcher".to_string())
         .spawn(move || {
             let _shutdown_guard = poller_token;
-            let mut watcher = QuoteWatcher::new(&db_path).expect("Couldn't create watcher");
-            while let Some(()) = notify_rx.blocking_recv() {
-                trace!("Handling poll request");
-                for quote in watcher
-                    .get_new_and_update_seen()
-                    .expect("Couldn't poll quotes")
-                {
-                    info!(id = quote.id, "Poller handing off found quote.");
-                    quote_tx.send(quote).expect("Couldn't send quote");
+            let shutdown = poller_shutdown;
+            let mut watcher = QuoteWatcher::new(&seen_db_path, &db_path)
+                .expect("Couldn't create watcher")
+                .with_stats_capacity(get_config().poll_stats_capacity);
+            info!(
+                schema = ?watcher.schema,
+                quotes = ?watcher.quote_count().ok(),
+                "Detected quotes db schema"
+            );
+            while let Some(request) = notify_rx.blocking_recv() {
+                let poll = panic::catch_unwind(AssertUnwindSafe(|| match request {
+                    WatcherRequest::Poll => {
+                        trace!("Handling poll request");
+                        METRICS.poller_checks.inc();
+                        for quote in watcher
+                            .get_new_and_update_seen()
+                            .expect("Couldn't poll quotes")
+                        {
+                            info!(id = quote.id, "Poller handing off found quote.");
+                            quote_tx.send(quote).expect("Couldn't send quote");
+                        }
+                        METRICS.observe_polls(watcher.stats());
+                    }
+                    WatcherRequest::Reset => {
+                        warn!("Resetting seen quotes, all quotes will be sent again");
+                        watcher.reset().expect("Couldn't reset seen quotes");
+                    }
+                    WatcherRequest::Reopen => {
+                        info!("Reopening the quotes db");
+                        watcher
+                            .reopen(&db_path)
+                            .expect("Couldn't reopen the quotes db");
+                        info!(
+                            schema = ?watcher.schema,
+                            quotes = ?watcher.quote_count().ok(),
+                            "Detected quotes db schema"
+                        );
+                        if let Err(e) = reopen_cache.reload() {
+                            error!("Couldn't reload the quote cache: {}", e);
+                        }
+                    }
+                }));
+                // shut down rather than carry on without a poller, so the
+                // bot gets restarted
+                if let Err(payload) = poll {
+                    error!("Quote poller panicked: {}", panic_message(&*payload));
+                    shutdown.shutdown();
+                    break;
                 }
+                health_tx.send_replace(Instant::now());
             }
         })?;
 
+    let timed_checks = send_timed_checks(notify_tx.clone(), poll_interval);
     let poll_task = async move {
-        while let Some(quote) = quote_rx.recv().await {
-            send_quote(&quote, &disc_http)
-                .await
-                .expect("Couldn't send quote");
+        let send_quotes = async {
+            // quotes wait in quote_rx for their turn, so a backlog is sent
+            // slowly rather than dropped
+            let mut throttle = tokio::time::interval(get_config().discord_send_interval);
+            throttle.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            while let Some(quote) = quote_rx.recv().await {
+                throttle.tick().await;
+                send_started_tx.send_replace(Some(Instant::now()));
+                let failed = send_quote(&quote, &*disc_http).await;
+                send_started_tx.send_replace(None);
+                if failed.len() < get_config().quotes_channel_ids.len() {
+                    notify_submitter(&quote, &*disc_http).await;
+                }
+                if !failed.is_empty() {
+                    error!(
+                        id = quote.id,
+                        "Giving up on sending quote to {} channels",
+                        failed.len()
+                    );
+                    if let Err(e) = record_failed_quote(&quote, &failed) {
+                        error!(id = quote.id, "Couldn't record failed quote: {}", e);
+                    }
+                }
+                quote_cache.push(quote);
+            }
+        };
+        // timed checks never finish, so this ends when the db_watcher thread does
+        tokio::select! {
+            () = send_quotes => {}
+            () = timed_checks => {}
         }
     };
-    let poll_task = shutdown.wrap_vital(poll_task);
+    let poll_task = shutdown.wrap_vital(poll_task.instrument(info_span!("poll_task")));
+
+    let health = PollerHealth {
+        last_check: health_rx,
+        send_started: send_started_rx,
+    };
+    Ok((notify_tx, health, poll_task))
+}
 
-    Ok((notify_tx, poll_task))
+/// Post a summary of the day's new quotes to the quotes channels every day
+/// at `time` after midnight UTC. Quotes newer than the last summary count as
+/// new, starting from the newest quote when this is first run.
+pub async fn run_daily_summary(http: std::sync::Arc<Http>, time: Duration) {
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    if let Err(e) = init_summary_watermark() {
+        error!("Couldn't set up the daily summary: {}", e);
+        return;
+    }
+    loop {
+        let since_midnight = Duration::from_secs(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("Clock is before 1970")
+                .as_secs()
+                % DAY.as_secs(),
+        );
+        let wait = match time.checked_sub(since_midnight) {
+            Some(wait) if !wait.is_zero() => wait,
+            _ => DAY + time - since_midnight,
+        };
+        tokio::time::sleep_until(tokio::time::Instant::now() + wait).await;
+        if let Err(e) = post_daily_summary(&http).await {
+            error!("Couldn't post the daily summary: {}", e);
+        }
+    }
+}
+
+fn init_summary_watermark() -> ah::Result<()> {
+    let conn = get_quotes_db()?;
+    conn.execute(
+        &format!(
+            "INSERT INTO daily_summary (last_quote_id)
+             SELECT IFNULL((SELECT MAX(id) FROM {}), 0)
+             WHERE NOT EXISTS (SELECT * FROM daily_summary)",
+            QuoteSchema::detect(&conn)?.table()
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+async fn post_daily_summary(http: &Http) -> ah::Result<()> {
+    let config = get_config();
+    let quotes = {
+        let conn = get_quotes_db()?;
+        let last_summarized: i64 =
+            conn.query_row("SELECT last_quote_id FROM daily_summary", [], |r| r.get(0))?;
+        get_range(&conn, last_summarized, i64::MAX)?
+    };
+    let Some(last) = quotes.last() else {
+        info!("No new quotes for the daily summary");
+        return Ok(());
+    };
+
+    let mut description = String::new();
+    for quote in &quotes {
+        let line = format!("**#{}** {}\n", quote.id, truncate_str(&quote.text, 100));
+        // descriptions are capped at 4096
+        if description.chars().count() + line.chars().count() > 4000 {
+            description += "…";
+            break;
+        }
+        description += &line;
+    }
+    if get_args().dry_run {
+        println!("[dry run] Would post daily summary:\n{}", description);
+        return Ok(());
+    }
+    for channel in &config.quotes_channel_ids {
+        channel
+            .send_message(http, |msg| {
+                msg.embed(|embed| {
+                    embed
+                        .title("Today's New Quotes")
+                        .description(&description)
+                        .color(config.default_color)
+                })
+            })
+            .await?;
+    }
+    get_quotes_db()?.execute("UPDATE daily_summary SET last_quote_id = ?1", [last.id])?;
+    info!(count = quotes.len(), "Posted daily summary");
+    Ok(())
+}
+
+/// What the watchdog watches: the db_watcher thread and the task sending
+/// the quotes it finds can each hang without the other noticing.
+pub struct PollerHealth {
+    /// When the db_watcher thread last finished a check.
+    last_check: watch::Receiver<Instant>,
+    /// When the quote being sent started sending, if one is.
+    send_started: watch::Receiver<Option<Instant>>,
+}
+
+/// Shut down if the poller doesn't finish a check, or gets stuck sending a
+/// quote, for `stall_timeout`, so that a hung poller gets the bot restarted
+/// rather than silently stopping quotes.
+pub fn spawn_watchdog(
+    mut health: PollerHealth,
+    stall_timeout: Duration,
+    shutdown: Shutdown,
+) -> tokio::task::JoinHandle<Option<()>> {
+    let watchdog = {
+        let shutdown = shutdown.clone();
+        async move {
+            let mut tick = tokio::time::interval(stall_timeout / 4);
+            loop {
+                tokio::select! {
+                    changed = health.last_check.changed() => {
+                        // the poller has exited, which shuts down on its own
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                    _ = tick.tick() => {}
+                }
+                let since_check = health.last_check.borrow().elapsed();
+                let sending_for = health.send_started.borrow().map(|start| start.elapsed());
+                let stalled = if since_check >= stall_timeout {
+                    format!("hasn't finished a check in {:?}", since_check)
+                } else if let Some(sending_for) = sending_for.filter(|&d| d >= stall_timeout) {
+                    format!("has been sending a quote for {:?}", sending_for)
+                } else {
+                    continue;
+                };
+                error!(
+                    severity = "CRITICAL",
+                    "CRITICAL: quote poller {}, shutting down", stalled
+                );
+                shutdown.shutdown();
+                return;
+            }
+        }
+    };
+    tokio::spawn(shutdown.wrap_cancel(watchdog))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// A bot db and a quotes db holding `quotes`, in temp files that last as
+    /// long as this does.
+    struct TestDbs {
+        seen_file: NamedTempFile,
+        quotes_file: NamedTempFile,
+        quotes_db: sql::Connection,
+    }
+
+    impl TestDbs {
+        fn quotes_path(&self) -> &str {
+            self.quotes_file.path().to_str().unwrap()
+        }
+
+        fn watcher(&self) -> QuoteWatcher {
+            QuoteWatcher::new(self.seen_file.path().to_str().unwrap(), self.quotes_path()).unwrap()
+        }
+
+        fn insert(&self, quotes: &[(i64, &str, Option<&str>)]) {
+            insert_quotes(&self.quotes_db, quotes);
+        }
+    }
+
+    fn test_dbs(quotes: &[(i64, &str, Option<&str>)]) -> TestDbs {
+        let seen_file = NamedTempFile::new().unwrap();
+        let quotes_file = NamedTempFile::new().unwrap();
+        let quotes_db = sql::Connection::open(quotes_file.path()).unwrap();
+        quotes_db
+            .execute(
+                "CREATE TABLE quotes (id INTEGER PRIMARY KEY, quote TEXT NOT NULL, tags TEXT)",
+                [],
+            )
+            .unwrap();
+        insert_quotes(&quotes_db, quotes);
+        TestDbs {
+            seen_file,
+            quotes_file,
+            quotes_db,
+        }
+    }
+
+    fn insert_quotes(quotes_db: &sql::Connection, quotes: &[(i64, &str, Option<&str>)]) {
+        for (id, text, tags) in quotes {
+            quotes_db
+                .execute(
+                    "INSERT INTO quotes (id, quote, tags) VALUES (?1, ?2, ?3)",
+                    sql::params![id, text, tags],
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn only_returns_unseen_quotes() {
+        let dbs = test_dbs(&[(1, "first", None), (2, "second", Some("a b"))]);
+
+        let mut watcher = dbs.watcher();
+        // quotes that existed before the watcher are never reported
+        assert_eq!(watcher.get_new_and_update_seen().unwrap().count(), 0);
+
+        dbs.insert(&[(3, "third", Some("c")), (4, "fourth", None)]);
+        let new: Vec<Quote> = watcher.get_new_and_update_seen().unwrap().collect();
+        assert_eq!(
+            new.iter()
+                .map(|q| (q.id, q.text.as_str()))
+                .collect::<Vec<_>>(),
+            [(3, "third"), (4, "fourth")]
+        );
+        assert_eq!(new[0].tags.as_deref(), Some("c"));
+
+        assert_eq!(watcher.get_new_and_update_seen().unwrap().count(), 0);
+    }
+    #[tokio::test]
+    async fn fs_watcher_polls_once_after_writes_settle() {
+        use std::io::Write;
+        let mut file = NamedTempFile::new().unwrap();
+        let (sender, mut polls) = mpsc::channel(POLL_CHANNEL_CAPACITY);
+        tokio::spawn(
+            configure_fs_watcher(
+                sender,
+                file.path().to_str().unwrap(),
+                Duration::from_millis(100),
+            )
+            .unwrap(),
+        );
+
+        for _ in 0..3 {
+            writeln!(file, "change").unwrap();
+            file.flush().unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        let last_write = Instant::now();
+        let poll = tokio::time::timeout(Duration::from_secs(5), polls.recv()).await;
+        assert_eq!(poll.unwrap(), Some(WatcherRequest::Poll));
+        // the poll waited for the writes to stop, and there was only one
+        assert!(last_write.elapsed() >= Duration::from_millis(60));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), polls.recv())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn watchdog_catches_a_stuck_send() {
+        let (check_tx, last_check) = watch::channel(Instant::now());
+        let (send_tx, send_started) = watch::channel(None);
+        let shutdown = Shutdown::new();
+        spawn_watchdog(
+            PollerHealth {
+                last_check,
+                send_started,
+            },
+            Duration::from_millis(200),
+            shutdown.clone(),
+        );
+        send_tx.send_replace(Some(Instant::now()));
+        // checks keep finishing, so only the send can be blamed
+        let checks = async {
+            loop {
+                check_tx.send_replace(Instant::now());
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::select! {
+            () = shutdown.wait_shutdown_triggered() => {}
+            () = checks => unreachable!(),
+            () = tokio::time::sleep(Duration::from_secs(5)) => panic!("watchdog didn't shut down"),
+        }
+    }
+
+    #[test]
+    fn detects_legacy_column_names() {
+        let seen_file = NamedTempFile::new().unwrap();
+        let quotes_file = NamedTempFile::new().unwrap();
+        let quotes_path = quotes_file.path().to_str().unwrap();
+        let quotes_db = sql::Connection::open(quotes_path).unwrap();
+        quotes_db
+            .execute(
+                "CREATE TABLE quotes (quote_id INTEGER PRIMARY KEY, text TEXT NOT NULL)",
+                [],
+            )
+            .unwrap();
+        quotes_db
+            .execute("INSERT INTO quotes VALUES (1, 'first')", [])
+            .unwrap();
+
+        let mut watcher =
+            QuoteWatcher::new(seen_file.path().to_str().unwrap(), quotes_path).unwrap();
+        assert_eq!(
+            watcher.schema,
+            QuoteSchema {
+                id: "quote_id",
+                text: "text",
+                tags: None,
+                author: None,
+            }
+        );
+        assert_eq!(watcher.get_new_and_update_seen().unwrap().count(), 0);
+
+        quotes_db
+            .execute("INSERT INTO quotes VALUES (2, 'second')", [])
+            .unwrap();
+        let new: Vec<Quote> = watcher.get_new_and_update_seen().unwrap().collect();
+        assert_eq!(new.len(), 1);
+        assert_eq!((new[0].id, new[0].text.as_str()), (2, "second"));
+        assert_eq!(new[0].tags, None);
+
+        // queries outside the watcher use the renamed columns too
+        let found = watcher
+            .db_conn
+            .query_row(
+                &format!(
+                    "SELECT id, quote, tags FROM {} WHERE quote LIKE 'sec%'",
+                    watcher.schema.table()
+                ),
+                [],
+                Quote::from_row,
+            )
+            .unwrap();
+        assert_eq!(found.id, 2);
+    }
+
+    /// Poll the way the watcher used to, checking every quote against the
+    /// seen table, for `bench_poll` to compare against.
+    fn poll_not_in(watcher: &mut QuoteWatcher) -> usize {
+        let tx = watcher.db_conn.transaction().unwrap();
+        let new = tx
+            .prepare(
+                &watcher
+                    .schema
+                    .select("WHERE id NOT IN (SELECT id FROM main.seen_quotes) ORDER BY id"),
+            )
+            .unwrap()
+            .query_map([], Quote::from_row)
+            .unwrap()
+            .count();
+        QuoteWatcher::update_seen(&tx, &watcher.schema).unwrap();
+        tx.commit().unwrap();
+        new
+    }
+
+    #[test]
+    #[ignore = "benchmark; run with --ignored --nocapture"]
+    fn bench_poll() {
+        const QUOTES: i64 = 10_000;
+        const POLLS: i64 = 200;
+        let mut dbs = test_dbs(&[]);
+        let tx = dbs.quotes_db.transaction().unwrap();
+        for id in 1..=QUOTES {
+            insert_quotes(
+                &tx,
+                &[(id, "a quote of typical length, give or take", None)],
+            );
+        }
+        tx.commit().unwrap();
+        let mut watcher = dbs.watcher();
+
+        // each poll finds the one quote added since the last
+        let mut next_id = QUOTES + 1;
+        let mut time_polls = |poll: fn(&mut QuoteWatcher) -> usize| {
+            let mut total = Duration::ZERO;
+            for _ in 0..POLLS {
+                dbs.insert(&[(next_id, "new", None)]);
+                next_id += 1;
+                let start = Instant::now();
+                assert_eq!(poll(&mut watcher), 1);
+                total += start.elapsed();
+            }
+            total / POLLS as u32
+        };
+        let not_in = time_polls(poll_not_in);
+        let by_id = time_polls(|watcher| watcher.get_new_and_update_seen().unwrap().count());
+        println!(
+            "{} quotes, mean of {} polls: NOT IN {:?}, by id {:?}",
+            QUOTES, POLLS, not_in, by_id
+        );
+        assert!(by_id < not_in);
+    }
+
+    #[test]
+    fn get_range_reads_without_marking_seen() {
+        let dbs = test_dbs(&[(1, "first", None)]);
+
+        let mut watcher = dbs.watcher();
+        dbs.insert(&[
+            (2, "second", None),
+            (3, "third", Some("c")),
+            (4, "fourth", None),
+        ]);
+        let ids = |quotes: Vec<Quote>| quotes.iter().map(|q| q.id).collect::<Vec<_>>();
+        // the start is exclusive and the end inclusive
+        assert_eq!(ids(get_range(&watcher.db_conn, 1, 3).unwrap()), [2, 3]);
+        assert_eq!(
+            ids(get_range(&watcher.db_conn, 0, i64::MAX).unwrap()),
+            [1, 2, 3, 4]
+        );
+        assert!(get_range(&watcher.db_conn, 4, 10).unwrap().is_empty());
+        assert!(get_range(&watcher.db_conn, 3, 1).unwrap().is_empty());
+        assert_eq!(
+            get_range(&watcher.db_conn, 2, 3).unwrap()[0]
+                .tags
+                .as_deref(),
+            Some("c")
+        );
+
+        // none of that counted as seeing them
+        assert!(watcher.is_new(2).unwrap());
+        assert_eq!(
+            watcher
+                .get_new_and_update_seen()
+                .unwrap()
+                .map(|q| q.id)
+                .collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn counts_seen_and_total_quotes() {
+        let dbs = test_dbs(&[(1, "first", None), (2, "second", None)]);
+
+        let mut watcher = dbs.watcher();
+        assert_eq!(watcher.quote_count().unwrap(), (2, 2));
+
+        dbs.insert(&[(3, "third", None)]);
+        assert_eq!(watcher.quote_count().unwrap(), (2, 3));
+        watcher.get_new_and_update_seen().unwrap().for_each(drop);
+        assert_eq!(watcher.quote_count().unwrap(), (3, 3));
+    }
+
+    #[test]
+    fn latest_id_is_none_without_quotes() {
+        let dbs = test_dbs(&[]);
+
+        let watcher = dbs.watcher();
+        assert_eq!(watcher.latest_id().unwrap(), None);
+    }
+
+    #[test]
+    fn latest_id_is_highest_seen() {
+        let dbs = test_dbs(&[(3, "third", None), (1, "first", None)]);
+
+        let mut watcher = dbs.watcher();
+        assert_eq!(watcher.latest_id().unwrap(), Some(3));
+
+        // unseen quotes don't count until they're polled
+        dbs.insert(&[(7, "seventh", None)]);
+        assert_eq!(watcher.latest_id().unwrap(), Some(3));
+        watcher.get_new_and_update_seen().unwrap().for_each(drop);
+        assert_eq!(watcher.latest_id().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn keeps_stats_for_recent_polls() {
+        let dbs = test_dbs(&[(1, "first", None)]);
+
+        let mut watcher = dbs.watcher().with_stats_capacity(2);
+        assert!(watcher.stats().is_empty());
+        watcher.get_new_and_update_seen().unwrap().for_each(drop);
+        dbs.insert(&[(2, "second", None), (3, "third", None)]);
+        watcher.get_new_and_update_seen().unwrap().for_each(drop);
+        dbs.insert(&[(4, "fourth", None)]);
+        watcher.get_new_and_update_seen().unwrap().for_each(drop);
+
+        // only the last two polls are kept
+        let polls: Vec<_> = watcher
+            .stats()
+            .iter()
+            .map(|poll| (poll.new_quotes, poll.total_seen))
+            .collect();
+        assert_eq!(polls, [(2, 3), (1, 4)]);
+
+        // shrinking drops the oldest
+        let watcher = watcher.with_stats_capacity(1);
+        let polls: Vec<_> = watcher
+            .stats()
+            .iter()
+            .map(|poll| (poll.new_quotes, poll.total_seen))
+            .collect();
+        assert_eq!(polls, [(1, 4)]);
+    }
+
+    #[test]
+    fn since_returns_and_marks_newer_quotes() {
+        let dbs = test_dbs(&[(1, "first", None), (2, "second", None)]);
+
+        let mut watcher = dbs.watcher();
+        dbs.insert(&[(3, "third", None), (4, "fourth", None)]);
+        let ids: Vec<i64> = watcher.since(2).unwrap().map(|q| q.id).collect();
+        assert_eq!(ids, [3, 4]);
+        assert_eq!(watcher.quote_count().unwrap(), (4, 4));
+        // already seen quotes are still returned
+        assert_eq!(watcher.since(0).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn is_new_until_polled() {
+        let dbs = test_dbs(&[(1, "first", None)]);
+
+        let mut watcher = dbs.watcher();
+        assert!(!watcher.is_new(1).unwrap());
+
+        dbs.insert(&[(2, "second", None)]);
+        assert!(watcher.is_new(2).unwrap());
+        watcher.get_new_and_update_seen().unwrap().for_each(drop);
+        assert!(!watcher.is_new(2).unwrap());
+        // ids that don't exist yet are new too
+        assert!(watcher.is_new(3).unwrap());
+    }
+
+    #[test]
+    fn reopen_attaches_replaced_quotes_db() {
+        let dbs = test_dbs(&[(1, "first", None)]);
+        let quotes_path = dbs.quotes_path();
+
+        let mut watcher = dbs.watcher();
+        assert_eq!(watcher.quote_count().unwrap(), (1, 1));
+        // like a pooled connection, which stays attached between uses
+        let pooled = sql::Connection::open(dbs.seen_file.path()).unwrap();
+        attach_current(&pooled, quotes_path).unwrap();
+        assert_eq!(quote_count(&pooled).unwrap(), (1, 1));
+
+        // replace the file, like a restore would
+        let replacement = test_dbs(&[(1, "first", None), (2, "second", None), (3, "third", None)]);
+        replacement.quotes_file.persist(quotes_path).unwrap();
+
+        watcher.reopen(quotes_path).unwrap();
+        assert_eq!(watcher.quote_count().unwrap(), (3, 3));
+        attach_current(&pooled, quotes_path).unwrap();
+        assert_eq!(quote_count(&pooled).unwrap(), (3, 3));
+        assert_eq!(watcher.get_new_and_update_seen().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn keeps_temp_store_in_memory() {
+        let dbs = test_dbs(&[]);
+
+        let watcher = dbs.watcher();
+        let temp_store: i64 = watcher
+            .db_conn
+            .query_row("PRAGMA temp_store", [], |r| r.get(0))
+            .unwrap();
+        // MEMORY
+        assert_eq!(temp_store, 2);
+    }
 }